@@ -1,29 +1,67 @@
-use std::cell::RefCell;
 use std::fmt::Debug;
-use std::rc::Weak;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use crate::draw::{DrawCommand, Framebuffer};
 use crate::value::{Property, Value};
 use crate::variable::Variables;
 use crate::vm::{VmError, VmResult};
 
-pub trait Building : Debug {
+/// A building linked to processors. Buildings may be shared by processors running on different
+/// threads, so implementations synchronize their own state.
+pub trait Building : Debug + Send + Sync {
     fn name(&self) -> &str;
 
+    /// Whether this is the processor executing the instruction, see [`ProcessorBuilding`].
+    /// Processors are read and written with `read_as` and `write_as` instead of `read` and `write`.
+    fn is_processor(&self) -> bool {
+        false
+    }
+
     fn print_flush(&self, _string: String) -> VmResult<()> {
         Err(VmError::InvalidBuildingType("print flush into", self.name().to_string()))
     }
 
+    fn draw_flush(&self, _commands: Vec<DrawCommand>) -> VmResult<()> {
+        Err(VmError::InvalidBuildingType("draw flush into", self.name().to_string()))
+    }
+
     fn read(&self, _index: Value) -> VmResult<Value> {
         Err(VmError::InvalidBuildingType("read from", self.name().to_string()))
     }
     fn write(&self, _index: Value, _value: Value) -> VmResult<()> {
         Err(VmError::InvalidBuildingType("write into", self.name().to_string()))
     }
+    /// Like `write`, but also returns the previous value if the building keeps it.
+    fn replace(&self, index: Value, value: Value) -> VmResult<Option<Value>> {
+        self.write(index, value).map(|()| None)
+    }
 
     fn sense(&self, _property: Property) -> VmResult<Value> {
         Err(VmError::InvalidBuildingType("sense from", self.name().to_string()))
     }
 }
 
+impl dyn Building + '_ {
+    /// Reads from the building on behalf of the processor with variables `vars`,
+    /// which is the processor `@this` refers to. All reads made by processors go through here.
+    pub fn read_as(&self, vars: &Variables, index: Value) -> VmResult<Value> {
+        if self.is_processor() {
+            ProcessorBuilding::read_var(vars, index)
+        } else {
+            self.read(index)
+        }
+    }
+
+    /// Writes into the building on behalf of the processor with variables `vars`, see `read_as`.
+    /// Returns the previous value if the building keeps it, see `Building::replace`.
+    pub fn write_as(&self, vars: &Variables, index: Value, value: Value) -> VmResult<Option<Value>> {
+        if self.is_processor() {
+            ProcessorBuilding::write_var(vars, index, value).map(Some)
+        } else {
+            self.replace(index, value)
+        }
+    }
+}
+
 impl PartialEq for dyn Building {
     fn eq(&self, other: &Self) -> bool {
         self.name() == other.name()
@@ -31,19 +69,41 @@ impl PartialEq for dyn Building {
 }
 impl Eq for dyn Building {}
 
+/// Locks `mutex`, ignoring poisoning: no write can leave building state half updated.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The processor itself, as seen through `@this`. It holds no reference to the processor: `read_as`
+/// and `write_as` resolve reads and writes against the variables of the processor executing them.
 #[derive(Debug)]
 pub struct ProcessorBuilding {
     name: String,
-    variables: Weak<Variables>,
 }
 
 impl ProcessorBuilding {
-    pub fn new(name: String, variables: Weak<Variables>) -> Self {
+    pub fn new(name: String) -> Self {
         ProcessorBuilding {
             name,
-            variables,
         }
     }
+
+    fn read_var(vars: &Variables, index: Value) -> VmResult<Value> {
+        let index = index.as_str()?;
+        vars.get_handle(index.as_string_ref())
+            .ok_or_else(|| VmError::VariableNotFound(index.to_string()))
+            .map(|h| h.val(vars))
+    }
+
+    /// Returns the previous value of the variable.
+    fn write_var(vars: &Variables, index: Value, value: Value) -> VmResult<Value> {
+        let index = index.as_str()?;
+        let handle = vars.get_handle(index.as_string_ref())
+            .ok_or_else(|| VmError::VariableNotFound(index.to_string()))?;
+        let old = handle.val(vars);
+        handle.set(vars, value)?;
+        Ok(old)
+    }
 }
 
 impl Building for ProcessorBuilding {
@@ -51,38 +111,31 @@ impl Building for ProcessorBuilding {
         &self.name
     }
 
-    fn read(&self, index: Value) -> VmResult<Value> {
-        let index = index.as_str()?;
-        let vars = self.variables.upgrade().unwrap();
-        vars.get_handle(index.as_string_ref())
-            .ok_or_else(|| VmError::VariableNotFound(index.to_string()))
-            .map(|h| h.val(&vars).clone())
-    }
-    fn write(&self, index: Value, value: Value) -> VmResult<()> {
-        let index = index.as_str()?;
-        let vars = self.variables.upgrade().unwrap();
-        vars.get_handle(index.as_string_ref())
-            .ok_or_else(|| VmError::VariableNotFound(index.to_string()))
-            .map(|h| h.set(&vars, value))?
+    fn is_processor(&self) -> bool {
+        true
     }
 }
 
 #[derive(Debug)]
 pub struct MessageBuilding {
     name: String,
-    text: RefCell<String>,
+    text: Mutex<String>,
 }
 
 impl MessageBuilding {
     pub fn new(name: String) -> Self {
         MessageBuilding {
             name,
-            text: RefCell::new("".to_string()),
+            text: Mutex::new("".to_string()),
         }
     }
 
     pub fn get_text(&self) -> String {
-        self.text.clone().into_inner()
+        lock(&self.text).clone()
+    }
+
+    pub fn set_text(&self, text: String) {
+        *lock(&self.text) = text;
     }
 }
 
@@ -92,7 +145,7 @@ impl Building for MessageBuilding {
     }
 
     fn print_flush(&self, string: String) -> VmResult<()> {
-        *self.text.borrow_mut() = string;
+        *lock(&self.text) = string;
         Ok(())
     }
 }
@@ -100,19 +153,30 @@ impl Building for MessageBuilding {
 #[derive(Debug)]
 pub struct MemoryBuilding {
     name: String,
-    data: RefCell<Box<[f64]>>,
+    data: Mutex<Box<[f64]>>,
 }
 
 impl MemoryBuilding {
     pub fn new(name: String, capacity: usize) -> Self {
         MemoryBuilding {
             name,
-            data: RefCell::new(vec![0.; capacity].into_boxed_slice()),
+            data: Mutex::new(vec![0.; capacity].into_boxed_slice()),
         }
     }
 
     pub fn get_data(&self) -> Box<[f64]> {
-        self.data.clone().into_inner()
+        lock(&self.data).clone()
+    }
+
+    /// Overwrites the first cells with `data`, leaving the rest unchanged.
+    /// Fails if `data` does not fit in the memory.
+    pub fn set_data(&self, data: &[f64]) -> VmResult<()> {
+        let mut cells = lock(&self.data);
+        if data.len() > cells.len() {
+            return Err(VmError::InvalidDeviceContents(self.name.clone()));
+        }
+        cells[..data.len()].copy_from_slice(data);
+        Ok(())
     }
 }
 
@@ -122,12 +186,51 @@ impl Building for MemoryBuilding {
     }
 
     fn read(&self, index: Value) -> VmResult<Value> {
-        index.do_index_copy(&self.data.borrow(), "memory cell").map(Value::Num)
+        index.do_index_copy(&lock(&self.data), "memory cell").map(Value::Num)
     }
     fn write(&self, index: Value, value: Value) -> VmResult<()> {
-        let idx = index.as_index(self.data.borrow().len(), "memory cell")?;
-        let val = value.as_num()?;
-        self.data.borrow_mut()[idx] = val;
+        self.replace(index, value).map(|_| ())
+    }
+    fn replace(&self, index: Value, value: Value) -> VmResult<Option<Value>> {
+        let mut data = lock(&self.data);
+        let idx = index.as_index(data.len(), "memory cell")?;
+        let old = std::mem::replace(&mut data[idx], value.as_num()?);
+        Ok(Some(Value::Num(old)))
+    }
+}
+
+#[derive(Debug)]
+pub struct DisplayBuilding {
+    name: String,
+    framebuffer: Mutex<Framebuffer>,
+}
+
+impl DisplayBuilding {
+    pub const LOGIC_DISPLAY_SIZE: usize = 80;
+    pub const LARGE_LOGIC_DISPLAY_SIZE: usize = 176;
+
+    pub fn new(name: String, size: usize) -> Self {
+        DisplayBuilding {
+            name,
+            framebuffer: Mutex::new(Framebuffer::new(size)),
+        }
+    }
+
+    pub fn get_framebuffer(&self) -> Framebuffer {
+        lock(&self.framebuffer).clone()
+    }
+}
+
+impl Building for DisplayBuilding {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn draw_flush(&self, commands: Vec<DrawCommand>) -> VmResult<()> {
+        let mut framebuffer = lock(&self.framebuffer);
+        for command in &commands {
+            framebuffer.draw(command);
+        }
         Ok(())
     }
 }
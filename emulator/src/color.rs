@@ -0,0 +1,107 @@
+/// An RGBA color with 8 bits per channel.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    /// Named colors accepted in `%[name]` literals.
+    pub const NAMED: &'static [(&'static str, u32)] = &[
+        ("clear", 0x00000000),
+        ("black", 0x000000ff),
+        ("white", 0xffffffff),
+        ("lightGray", 0xbfbfbfff),
+        ("gray", 0x7f7f7fff),
+        ("darkGray", 0x3f3f3fff),
+        ("blue", 0x0000ffff),
+        ("navy", 0x00007fff),
+        ("royal", 0x4169e1ff),
+        ("slate", 0x708090ff),
+        ("sky", 0x87ceebff),
+        ("cyan", 0x00ffffff),
+        ("teal", 0x007f7fff),
+        ("green", 0x00ff00ff),
+        ("acid", 0x7fff00ff),
+        ("lime", 0x32cd32ff),
+        ("forest", 0x228b22ff),
+        ("olive", 0x6b8e23ff),
+        ("yellow", 0xffff00ff),
+        ("gold", 0xffd700ff),
+        ("goldenrod", 0xdaa520ff),
+        ("orange", 0xffa500ff),
+        ("brown", 0x8b4513ff),
+        ("tan", 0xd2b48cff),
+        ("brick", 0xb22222ff),
+        ("red", 0xff0000ff),
+        ("scarlet", 0xff341cff),
+        ("crimson", 0xdc143cff),
+        ("coral", 0xff7f50ff),
+        ("salmon", 0xfa8072ff),
+        ("pink", 0xff69b4ff),
+        ("magenta", 0xff00ffff),
+        ("purple", 0xa020f0ff),
+        ("violet", 0xee82eeff),
+        ("maroon", 0xb03060ff),
+        ("accent", 0xffd37fff),
+    ];
+
+    pub fn from_rgba8888(rgba: u32) -> Self {
+        let [r, g, b, a] = rgba.to_be_bytes();
+        Color { r, g, b, a }
+    }
+
+    pub fn to_rgba8888(self) -> u32 {
+        u32::from_be_bytes([self.r, self.g, self.b, self.a])
+    }
+
+    /// Packs the color into a double the same way the game does,
+    /// by reinterpreting the RGBA8888 integer as the bits of a double.
+    pub fn to_double_bits(self) -> f64 {
+        f64::from_bits(self.to_rgba8888() as u64)
+    }
+
+    pub fn from_double_bits(value: f64) -> Self {
+        Self::from_rgba8888(value.to_bits() as u32)
+    }
+
+    /// Looks up a named color, ignoring case.
+    pub fn named(name: &str) -> Option<Self> {
+        Self::NAMED.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, rgba)| Self::from_rgba8888(*rgba))
+    }
+
+    /// Parses `rrggbb` or `rrggbbaa` hex notation.
+    pub fn parse_hex(hex: &str) -> Option<Self> {
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.bytes().all(|ch| ch.is_ascii_hexdigit()) {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some(Color {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+            a: if hex.len() == 8 { channel(6)? } else { 255 },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_literals() {
+        assert_eq!(Color::parse_hex("ff8000"), Some(Color { r: 255, g: 128, b: 0, a: 255 }));
+        assert_eq!(Color::parse_hex("01020304"), Some(Color { r: 1, g: 2, b: 3, a: 4 }));
+        assert_eq!(Color::parse_hex("ff80"), None);
+        assert_eq!(Color::parse_hex("gg8000"), None);
+        assert_eq!(Color::named("RED"), Some(Color { r: 255, g: 0, b: 0, a: 255 }));
+        assert_eq!(Color::named("nothing"), None);
+        let color = Color { r: 12, g: 34, b: 56, a: 78 };
+        assert_eq!(Color::from_double_bits(color.to_double_bits()), color);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::profile::Profile;
+
+#[derive(Debug, Default)]
+struct BranchCounts {
+    line: usize,
+    executions: u64,
+    taken: u64,
+}
+
+/// Line and branch coverage of one program, accumulated over any number of runs.
+///
+/// Every conditional jump forms two branches, taken and not taken.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    /// Executions of the most executed instruction of each line in every run, summed over the runs.
+    lines: BTreeMap<usize, u64>,
+    /// Counts of every conditional jump, by instruction index.
+    branches: BTreeMap<usize, BranchCounts>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the counts of a run. All profiles must come from the same program.
+    pub fn add(&mut self, profile: &Profile) {
+        let mut lines = BTreeMap::<usize, u64>::new();
+        for ins in &profile.instructions {
+            let line = lines.entry(ins.line).or_default();
+            *line = (*line).max(ins.executions);
+            if let Some(taken) = ins.jumps_taken {
+                let branch = self.branches.entry(ins.index).or_default();
+                branch.line = ins.line;
+                branch.executions += ins.executions;
+                branch.taken += taken;
+            }
+        }
+        for (line, count) in lines {
+            *self.lines.entry(line).or_default() += count;
+        }
+    }
+
+    fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|count| **count > 0).count()
+    }
+
+    fn branch_counts(branch: &BranchCounts) -> [u64; 2] {
+        [branch.taken, branch.executions - branch.taken]
+    }
+
+    fn branches_hit(&self) -> usize {
+        self.branches.values()
+            .flat_map(Self::branch_counts)
+            .filter(|count| *count > 0)
+            .count()
+    }
+
+    /// Formats the coverage as an lcov tracefile for the source file at `path`.
+    pub fn to_lcov(&self, path: &str) -> String {
+        let mut out = format!("TN:\nSF:{}\n", path);
+        for (index, branch) in &self.branches {
+            for (i, count) in Self::branch_counts(branch).into_iter().enumerate() {
+                if branch.executions == 0 {
+                    writeln!(out, "BRDA:{},{},{},-", branch.line, index, i).unwrap();
+                } else {
+                    writeln!(out, "BRDA:{},{},{},{}", branch.line, index, i, count).unwrap();
+                }
+            }
+        }
+        writeln!(out, "BRF:{}\nBRH:{}", self.branches.len() * 2, self.branches_hit()).unwrap();
+        for (line, count) in &self.lines {
+            writeln!(out, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(out, "LF:{}\nLH:{}\nend_of_record", self.lines.len(), self.lines_hit()).unwrap();
+        out
+    }
+
+    /// Formats the coverage as a Cobertura XML report for the source file at `path`.
+    pub fn to_cobertura(&self, path: &str) -> String {
+        let rate = |hit: usize, valid: usize| if valid == 0 { 1. } else { hit as f64 / valid as f64 };
+        let line_rate = rate(self.lines_hit(), self.lines.len());
+        let branch_rate = rate(self.branches_hit(), self.branches.len() * 2);
+        let rates = format!(r#"line-rate="{:.4}" branch-rate="{:.4}""#, line_rate, branch_rate);
+        let path = escape_xml(path);
+
+        let mut out = String::from("<?xml version=\"1.0\" ?>\n");
+        writeln!(out, r#"<coverage {} lines-covered="{}" lines-valid="{}" branches-covered="{}" branches-valid="{}" complexity="0" version="0" timestamp="0">"#,
+                 rates, self.lines_hit(), self.lines.len(), self.branches_hit(), self.branches.len() * 2).unwrap();
+        out.push_str("  <sources><source>.</source></sources>\n  <packages>\n");
+        writeln!(out, r#"    <package name="mlog" {} complexity="0">"#, rates).unwrap();
+        writeln!(out, r#"      <classes><class name="{}" filename="{}" {} complexity="0">"#, path, path, rates).unwrap();
+        out.push_str("        <methods/>\n        <lines>\n");
+        for (line, count) in &self.lines {
+            let branches = self.branches.values()
+                .filter(|branch| branch.line == *line)
+                .flat_map(Self::branch_counts)
+                .collect::<Vec<_>>();
+            if branches.is_empty() {
+                writeln!(out, r#"          <line number="{}" hits="{}"/>"#, line, count).unwrap();
+            } else {
+                let hit = branches.iter().filter(|count| **count > 0).count();
+                writeln!(out, r#"          <line number="{}" hits="{}" branch="true" condition-coverage="{}% ({}/{})"/>"#,
+                         line, count, hit * 100 / branches.len(), hit, branches.len()).unwrap();
+            }
+        }
+        out.push_str("        </lines>\n      </class></classes>\n    </package>\n  </packages>\n</coverage>\n");
+        out
+    }
+}
+
+fn escape_xml(string: &str) -> String {
+    string.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage_lcov() {
+        use crate::vm::VM;
+
+        let code = "set i 0\nloop:\nop add i i 1\njump loop lessThan i 3\njump end always\nprint i\nend:\nend";
+        let mut vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        vm.set_profiling(true);
+        vm.run(None, None, true).unwrap();
+        let mut coverage = Coverage::new();
+        coverage.add(&vm.profile().unwrap());
+        assert_eq!(coverage.to_lcov("test.mlog"), "TN:\nSF:test.mlog\n\
+            BRDA:4,2,0,2\nBRDA:4,2,1,1\nBRF:2\nBRH:2\n\
+            DA:1,1\nDA:3,3\nDA:4,3\nDA:5,1\nDA:6,0\nDA:8,1\nLF:6\nLH:5\nend_of_record\n");
+
+        // a run that never loops leaves the taken branch uncovered
+        let mut vm = VM::new(&code.replace("lessThan i 3", "lessThan i 1"), VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        vm.set_profiling(true);
+        vm.run(None, None, true).unwrap();
+        let mut coverage = Coverage::new();
+        coverage.add(&vm.profile().unwrap());
+        let report = coverage.to_cobertura("test.mlog");
+        assert!(report.contains(r#"<line number="4" hits="1" branch="true" condition-coverage="50% (1/2)"/>"#));
+        assert!(report.contains(r#"lines-covered="5" lines-valid="6" branches-covered="1" branches-valid="2""#));
+
+        coverage.add(&vm.profile().unwrap());
+        assert!(coverage.to_lcov("test.mlog").contains("BRDA:4,2,0,0\nBRDA:4,2,1,2\n"));
+        assert!(coverage.to_lcov("test.mlog").contains("DA:1,2\n"));
+    }
+}
@@ -0,0 +1,485 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use strum_macros::EnumString;
+use crate::instruction::{CellWrite, Instruction, SourceSpan};
+use crate::value::Value;
+use crate::vm::{PosVmResult, VmFinishReason, WriteObserver, VM};
+
+/// Where a breakpoint pauses execution, before the instruction there is executed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BreakpointLocation {
+    Instruction(usize),
+    /// 1-based source line.
+    Line(usize),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, EnumString)]
+#[strum(serialize_all = "camelCase")]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanEq,
+    GreaterThan,
+    GreaterThanEq,
+}
+
+/// Compares the value of a variable with a constant.
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub variable: String,
+    pub comparison: Comparison,
+    pub value: Value,
+}
+
+impl Condition {
+    /// Missing variables and ordering comparisons of values that are not numbers never match.
+    pub fn matches(&self, vm: &VM) -> bool {
+        let Ok(var) = vm.get_val(&self.variable) else {
+            return false;
+        };
+        match self.comparison {
+            Comparison::Equal => var == self.value,
+            Comparison::NotEqual => var != self.value,
+            comparison => {
+                let (Ok(a), Ok(b)) = (var.as_num(), self.value.as_num()) else {
+                    return false;
+                };
+                match comparison {
+                    Comparison::LessThan => a < b,
+                    Comparison::LessThanEq => a <= b,
+                    Comparison::GreaterThan => a > b,
+                    _ => a >= b,
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub location: BreakpointLocation,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn new(location: BreakpointLocation) -> Self {
+        Breakpoint {
+            location,
+            condition: None,
+        }
+    }
+
+    pub fn with_condition(location: BreakpointLocation, condition: Condition) -> Self {
+        Breakpoint {
+            location,
+            condition: Some(condition),
+        }
+    }
+
+    fn hit(&self, vm: &VM, pc: usize) -> bool {
+        let at = match self.location {
+            BreakpointLocation::Instruction(index) => index == pc,
+            BreakpointLocation::Line(line) => vm.source_span(pc).is_some_and(|span| span.line == line),
+        };
+        at && self.condition.as_ref().is_none_or(|condition| condition.matches(vm))
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum WatchTarget {
+    /// Triggers when the value of the variable changes.
+    Variable(String),
+    /// Triggers when `write` stores into the cell, even if its value stays the same.
+    /// Fractional indices address the cell they are truncated to, like in the game.
+    Cell { building: String, index: usize },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatchAction {
+    /// Pauses execution after the instruction that triggered the watchpoint.
+    Break,
+    /// Only records the event.
+    Log,
+}
+
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub action: WatchAction,
+}
+
+/// A change observed by a watchpoint.
+#[derive(Debug, Clone)]
+pub struct WatchEvent {
+    pub watchpoint: usize,
+    pub target: WatchTarget,
+    /// Previous value, unknown for cells of buildings that do not keep their values.
+    pub old: Option<Value>,
+    pub new: Value,
+    /// Processor that made the change, as given to `VM::set_write_observer`.
+    /// Always 0 in a `Debugger`, the index of the processor in a `World`.
+    pub processor: usize,
+    /// Index of the instruction that made the change.
+    pub index: usize,
+    pub span: SourceSpan,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Records the writes into watched cells made by the processors it observes, as they happen.
+/// A `Debugger` uses one for its processor. Attached to a `World` with `World::set_write_observer`,
+/// it tells which of several processors wrote into a cell.
+#[derive(Debug, Default)]
+pub struct CellWatcher {
+    cells: Mutex<BTreeMap<usize, (String, usize)>>,
+    events: Mutex<Vec<WatchEvent>>,
+}
+
+impl CellWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Watches cell `index` of the building named `building`, events report `id` as their watchpoint.
+    pub fn watch(&self, id: usize, building: String, index: usize) {
+        lock(&self.cells).insert(id, (building, index));
+    }
+
+    pub fn unwatch(&self, id: usize) {
+        lock(&self.cells).remove(&id);
+    }
+
+    /// Returns the events recorded since the last call, oldest first.
+    pub fn take_events(&self) -> Vec<WatchEvent> {
+        std::mem::take(&mut lock(&self.events))
+    }
+}
+
+impl WriteObserver for CellWatcher {
+    fn written(&self, processor: usize, index: usize, span: SourceSpan, write: &CellWrite) {
+        // the cell a memory picks, and the truncated index for other buildings
+        let Ok(cell) = write.index.as_int().or_else(|_| write.index.as_num().map(|num| num.trunc() as i64)) else {
+            return;
+        };
+        let cells = lock(&self.cells);
+        let mut events = lock(&self.events);
+        for (id, (building, watched)) in cells.iter() {
+            if write.building.name() == building && cell == *watched as i64 {
+                events.push(WatchEvent {
+                    watchpoint: *id,
+                    target: WatchTarget::Cell { building: building.clone(), index: *watched },
+                    old: write.old.clone(),
+                    new: write.value.clone(),
+                    processor,
+                    index,
+                    span,
+                });
+            }
+        }
+    }
+}
+
+/// Runs a processor alone, pausing at breakpoints and watchpoints.
+///
+/// Every method that executes instructions returns `VmFinishReason::Breakpoint`
+/// when paused, after which execution can be resumed. Breakpoints are checked
+/// before an instruction is executed, except for the first one of each call.
+/// Watchpoints record their events as the processor runs,
+/// which are kept until taken with `take_watch_events`.
+#[derive(Debug)]
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    cells: Arc<CellWatcher>,
+    watch_events: RefCell<Vec<WatchEvent>>,
+    next_id: usize,
+    tick_limit: Option<u64>,
+    end_on_wrap: bool,
+}
+
+impl Debugger {
+    /// Takes over the processor, replacing its write observer.
+    pub fn new(mut vm: VM) -> Self {
+        let cells = Arc::new(CellWatcher::new());
+        vm.set_write_observer(0, Some(cells.clone()));
+        Debugger {
+            vm,
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            cells,
+            watch_events: RefCell::new(vec![]),
+            next_id: 0,
+            tick_limit: None,
+            end_on_wrap: false,
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn into_vm(mut self) -> VM {
+        self.vm.set_write_observer(0, None);
+        self.vm
+    }
+
+    pub fn set_tick_limit(&mut self, tick_limit: Option<u64>) {
+        self.tick_limit = tick_limit;
+    }
+
+    pub fn set_end_on_wrap(&mut self, end_on_wrap: bool) {
+        self.end_on_wrap = end_on_wrap;
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Adds a breakpoint and returns its id.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id();
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    /// Removes a breakpoint, returning it if it existed.
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints.iter().map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Adds a watchpoint and returns its id, which is distinct from the ids of breakpoints.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_id();
+        if let WatchTarget::Cell { building, index } = &watchpoint.target {
+            self.cells.watch(id, building.clone(), *index);
+        }
+        self.watchpoints.insert(id, watchpoint);
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint> {
+        self.cells.unwatch(id);
+        self.watchpoints.remove(&id)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints.iter().map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    /// Returns the events recorded since the last call, oldest first.
+    pub fn take_watch_events(&self) -> Vec<WatchEvent> {
+        self.watch_events.take()
+    }
+
+    /// Returns whether a breakpoint pauses execution before the next instruction.
+    pub fn at_breakpoint(&self) -> bool {
+        self.vm.pc().is_some_and(|pc| self.breakpoints.values().any(|b| b.hit(&self.vm, pc)))
+    }
+
+    /// Executes a single instruction, ignoring breakpoints and watchpoint actions.
+    /// Returns the reason the run finished, if it did.
+    pub fn step(&self) -> PosVmResult<Option<VmFinishReason>> {
+        self.step_watched().map(|(reason, _)| reason)
+    }
+
+    /// Executes a single instruction and records watch events, also those of an instruction that failed.
+    /// Also returns whether a watchpoint asks to pause.
+    fn step_watched(&self) -> PosVmResult<(Option<VmFinishReason>, bool)> {
+        let Some(index) = self.vm.pc().filter(|_| !self.watchpoints.is_empty()) else {
+            return Ok((self.vm.step(self.tick_limit, self.end_on_wrap)?, false));
+        };
+        let variables = self.watchpoints.iter()
+            .filter_map(|(id, watchpoint)| match &watchpoint.target {
+                WatchTarget::Variable(name) => self.vm.get_val(name).ok().map(|old| (*id, watchpoint, name, old)),
+                WatchTarget::Cell { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        let reason = self.vm.step(self.tick_limit, self.end_on_wrap);
+        let mut events = self.cells.take_events();
+        for (id, watchpoint, name, old) in variables {
+            let new = self.vm.get_val(name).unwrap();
+            if old != new {
+                events.push(WatchEvent {
+                    watchpoint: id,
+                    target: watchpoint.target.clone(),
+                    old: Some(old),
+                    new,
+                    processor: 0,
+                    index,
+                    span: self.vm.source_span(index).unwrap(),
+                });
+            }
+        }
+        let pause = events.iter()
+            .any(|event| self.watchpoints.get(&event.watchpoint).is_some_and(|w| w.action == WatchAction::Break));
+        self.watch_events.borrow_mut().extend(events);
+        reason.map(|reason| (reason, pause))
+    }
+
+    /// Like `step`, but a taken jump is followed until execution returns to the instruction
+    /// after it, which steps over subroutine calls. The instruction after the last one is the first.
+    pub fn step_over_jump(&self, limit: Option<usize>) -> PosVmResult<VmFinishReason> {
+        match self.vm.pc() {
+            Some(pc) if matches!(self.vm.instruction(pc), Some(Instruction::Jump(..))) => {
+                let next = if self.vm.instruction(pc + 1).is_some() { pc + 1 } else { 0 };
+                self.run_while(limit, |vm| vm.pc() != Some(next))
+            },
+            _ => Ok(self.step_watched()?.0.unwrap_or(VmFinishReason::Breakpoint)),
+        }
+    }
+
+    /// Runs until a breakpoint is hit or the run finishes.
+    pub fn r#continue(&self, limit: Option<usize>) -> PosVmResult<VmFinishReason> {
+        self.run_while(limit, |_| true)
+    }
+
+    /// Runs until the instruction at `index` is about to be executed, a breakpoint is hit
+    /// or the run finishes.
+    pub fn run_until(&self, index: usize, limit: Option<usize>) -> PosVmResult<VmFinishReason> {
+        self.run_while(limit, |vm| vm.pc() != Some(index))
+    }
+
+    fn run_while(&self, limit: Option<usize>, running: impl Fn(&VM) -> bool) -> PosVmResult<VmFinishReason> {
+        for executed in 0..limit.unwrap_or(usize::MAX) {
+            if executed > 0 && (!running(&self.vm) || self.at_breakpoint()) {
+                return Ok(VmFinishReason::Breakpoint);
+            }
+            match self.step_watched()? {
+                (Some(reason), _) => return Ok(reason),
+                (None, true) => return Ok(VmFinishReason::Breakpoint),
+                (None, false) => {},
+            }
+        }
+        Ok(VmFinishReason::InsLimit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debugger_breakpoints() {
+        let code = "set i 0\nop add i i 1\njump 1 lessThan i 5\nstop";
+        let mut debugger = Debugger::new(VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap());
+        let id = debugger.add_breakpoint(Breakpoint::new(BreakpointLocation::Instruction(2)));
+        assert!(matches!(debugger.r#continue(None), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().pc(), Some(2));
+        assert_eq!(debugger.vm().get_val("i").unwrap(), Value::Num(1.));
+        assert!(matches!(debugger.r#continue(None), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().get_val("i").unwrap(), Value::Num(2.));
+
+        debugger.remove_breakpoint(id);
+        debugger.add_breakpoint(Breakpoint::with_condition(BreakpointLocation::Line(2), Condition {
+            variable: "i".to_string(),
+            comparison: Comparison::Equal,
+            value: Value::Num(4.),
+        }));
+        assert!(matches!(debugger.r#continue(None), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().pc(), Some(1));
+        assert_eq!(debugger.vm().get_val("i").unwrap(), Value::Num(4.));
+        assert!(matches!(debugger.r#continue(None), Ok(VmFinishReason::Halt)));
+    }
+
+    #[test]
+    fn test_debugger_stepping() {
+        let code = "set r 2\njump 4 always\nprint \"b\"\nstop\nprint \"a\"\nset @counter r";
+        let debugger = Debugger::new(VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap());
+        assert!(matches!(debugger.step(), Ok(None)));
+        assert_eq!(debugger.vm().pc(), Some(1));
+        assert!(matches!(debugger.step_over_jump(None), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().pc(), Some(2));
+        assert!(matches!(debugger.run_until(3, None), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().pc(), Some(3));
+        assert!(matches!(debugger.r#continue(Some(10)), Ok(VmFinishReason::Halt)));
+        assert_eq!(debugger.into_vm().into_print_buffer().take(), "ab");
+
+        // the instruction after a trailing jump is the first one
+        let code = "print \"a\"\nstop\nprint \"b\"\nset @counter 0\njump 2 always";
+        let debugger = Debugger::new(VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap());
+        debugger.vm().set_var("@counter", Value::Num(4.)).unwrap();
+        assert!(matches!(debugger.step_over_jump(Some(10)), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().pc(), Some(0));
+        assert_eq!(debugger.into_vm().into_print_buffer().take(), "b");
+    }
+
+    #[test]
+    fn test_debugger_watchpoints() {
+        use std::sync::Arc;
+        use crate::building::MemoryBuilding;
+
+        let cell = Arc::new(MemoryBuilding::new("cell1".to_string(), 4));
+        let code = "set x 1\nwrite x cell1 2\nwrite x cell1 2\nset x 1\nop add x x 1\nwrite 5 cell1 1\nstop";
+        let mut debugger = Debugger::new(VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![cell]).unwrap());
+        let cell_watch = debugger.add_watchpoint(Watchpoint {
+            target: WatchTarget::Cell { building: "cell1".to_string(), index: 2 },
+            action: WatchAction::Log,
+        });
+        let var_watch = debugger.add_watchpoint(Watchpoint {
+            target: WatchTarget::Variable("x".to_string()),
+            action: WatchAction::Break,
+        });
+        assert!(matches!(debugger.r#continue(None), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().pc(), Some(1));
+        let events = debugger.take_watch_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].watchpoint, events[0].index), (var_watch, 0));
+        assert_eq!((&events[0].old, &events[0].new), (&Some(Value::Null), &Value::Num(1.)));
+
+        // writes of an unchanged value still trigger cell watchpoints, but not variable ones
+        assert!(matches!(debugger.r#continue(None), Ok(VmFinishReason::Breakpoint)));
+        assert_eq!(debugger.vm().pc(), Some(5));
+        let events = debugger.take_watch_events();
+        assert_eq!(events.iter().map(|e| (e.watchpoint, e.index)).collect::<Vec<_>>(),
+                   [(cell_watch, 1), (cell_watch, 2), (var_watch, 4)]);
+        assert_eq!((&events[0].old, &events[0].new), (&Some(Value::Num(0.)), &Value::Num(1.)));
+        assert_eq!((&events[1].old, &events[1].new), (&Some(Value::Num(1.)), &Value::Num(1.)));
+        assert_eq!(events[2].span.line, 5);
+        assert!(matches!(debugger.r#continue(None), Ok(VmFinishReason::Halt)));
+        assert!(debugger.take_watch_events().is_empty());
+    }
+
+    #[test]
+    fn test_debugger_watch_failing_step() {
+        use std::io::Write;
+
+        /// Fails every write, so tracing fails after the instruction has run.
+        struct BrokenWriter;
+
+        impl Write for BrokenWriter {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut vm = VM::new("set x 1\nstop", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        vm.set_trace(Some(Box::new(BrokenWriter)));
+        let mut debugger = Debugger::new(vm);
+        let watch = debugger.add_watchpoint(Watchpoint {
+            target: WatchTarget::Variable("x".to_string()),
+            action: WatchAction::Log,
+        });
+        assert!(debugger.r#continue(None).is_err());
+        let events = debugger.take_watch_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].watchpoint, events[0].index, &events[0].new), (watch, 0, &Value::Num(1.)));
+    }
+}
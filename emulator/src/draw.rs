@@ -0,0 +1,419 @@
+use std::cell::RefCell;
+use crate::color::Color;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DrawCommand {
+    Clear(Color),
+    Color(Color),
+    Stroke(i32),
+    Line(i32, i32, i32, i32),
+    Rect(i32, i32, i32, i32),
+    LineRect(i32, i32, i32, i32),
+    Poly(i32, i32, i32, i32, i32),
+    LinePoly(i32, i32, i32, i32, i32),
+    Triangle(i32, i32, i32, i32, i32, i32),
+    Print(i32, i32, Align, String),
+}
+
+/// Text alignment, with the same bit values as the game uses.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Align(pub u8);
+
+impl Align {
+    pub const CENTER: u8 = 1;
+    pub const TOP: u8 = 2;
+    pub const BOTTOM: u8 = 4;
+    pub const LEFT: u8 = 8;
+    pub const RIGHT: u8 = 16;
+
+    pub const NAMES: &'static [(&'static str, u8)] = &[
+        ("center", Self::CENTER),
+        ("top", Self::TOP),
+        ("bottom", Self::BOTTOM),
+        ("left", Self::LEFT),
+        ("right", Self::RIGHT),
+        ("topLeft", Self::TOP | Self::LEFT),
+        ("topRight", Self::TOP | Self::RIGHT),
+        ("bottomLeft", Self::BOTTOM | Self::LEFT),
+        ("bottomRight", Self::BOTTOM | Self::RIGHT),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().find(|(n, _)| *n == name).map(|(_, v)| Align(*v))
+    }
+}
+
+/// Graphics commands queued by a processor until the next `drawflush`.
+#[derive(Debug, Default)]
+pub struct DrawBuffer {
+    commands: RefCell<Vec<DrawCommand>>,
+}
+
+impl DrawBuffer {
+    /// Maximum number of queued commands, further commands are dropped like in the game.
+    pub const LIMIT: usize = 256;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, command: DrawCommand) {
+        let mut commands = self.commands.borrow_mut();
+        if commands.len() < Self::LIMIT {
+            commands.push(command);
+        }
+    }
+
+    pub fn take(&self) -> Vec<DrawCommand> {
+        self.commands.take()
+    }
+}
+
+/// 3x5 pixel glyphs, one row per entry from the top, most significant bit on the left.
+/// Lowercase letters are drawn as uppercase, unknown characters as a filled box.
+const FONT: &[(char, [u8; 5])] = &[
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('"', [0b101, 0b101, 0b000, 0b000, 0b000]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('*', [0b000, 0b101, 0b010, 0b101, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    (';', [0b000, 0b010, 0b000, 0b010, 0b100]),
+    ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+    ('?', [0b110, 0b001, 0b010, 0b000, 0b010]),
+    ('[', [0b110, 0b100, 0b100, 0b100, 0b110]),
+    (']', [0b011, 0b001, 0b001, 0b001, 0b011]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('|', [0b010, 0b010, 0b010, 0b010, 0b010]),
+];
+
+const GLYPH_WIDTH: i32 = 3;
+const GLYPH_HEIGHT: i32 = 5;
+const GLYPH_ADVANCE: i32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: i32 = GLYPH_HEIGHT + 1;
+
+fn glyph(ch: char) -> [u8; 5] {
+    let ch = ch.to_ascii_uppercase();
+    FONT.iter().find(|(c, _)| *c == ch).map_or([0b111; 5], |(_, rows)| *rows)
+}
+
+/// Pixels of a display. The origin is in the bottom left corner, like in the game.
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    size: usize,
+    pixels: Vec<Color>,
+    color: Color,
+    stroke: f64,
+}
+
+impl Framebuffer {
+    pub const BACKGROUND: Color = Color { r: 0, g: 0, b: 0, a: 255 };
+
+    pub fn new(size: usize) -> Self {
+        Framebuffer {
+            size,
+            pixels: vec![Self::BACKGROUND; size * size],
+            color: Color { r: 255, g: 255, b: 255, a: 255 },
+            stroke: 1.,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.size + x]
+    }
+
+    fn blend(&mut self, x: i32, y: i32) {
+        if x < 0 || y < 0 || x as usize >= self.size || y as usize >= self.size {
+            return;
+        }
+        let dst = &mut self.pixels[y as usize * self.size + x as usize];
+        let src = self.color;
+        let alpha = src.a as u32;
+        let mix = |s: u8, d: u8| ((s as u32 * alpha + d as u32 * (255 - alpha) + 127) / 255) as u8;
+        *dst = Color {
+            r: mix(src.r, dst.r),
+            g: mix(src.g, dst.g),
+            b: mix(src.b, dst.b),
+            a: (alpha + (dst.a as u32 * (255 - alpha) + 127) / 255) as u8,
+        };
+    }
+
+    /// Fills every pixel whose center lies inside the polygon.
+    fn fill_polygon(&mut self, points: &[(f64, f64)]) {
+        if points.len() < 3 {
+            return;
+        }
+        let (min_x, max_x) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), p| (lo.min(p.0), hi.max(p.0)));
+        let (min_y, max_y) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), p| (lo.min(p.1), hi.max(p.1)));
+        let limit = self.size as f64;
+        for y in min_y.max(0.).floor() as i32..max_y.min(limit).ceil() as i32 {
+            let cy = y as f64 + 0.5;
+            for x in min_x.max(0.).floor() as i32..max_x.min(limit).ceil() as i32 {
+                let cx = x as f64 + 0.5;
+                let mut inside = false;
+                for (i, &(x1, y1)) in points.iter().enumerate() {
+                    let (x2, y2) = points[(i + 1) % points.len()];
+                    if (y1 <= cy) != (y2 <= cy) && cx < x1 + (cy - y1) / (y2 - y1) * (x2 - x1) {
+                        inside = !inside;
+                    }
+                }
+                if inside {
+                    self.blend(x, y);
+                }
+            }
+        }
+    }
+
+    /// Draws a line with square caps using the current stroke.
+    /// Integer coordinates are treated as pixel centers.
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) {
+        let (dx, dy) = (x2 - x1, y2 - y1);
+        let len = (dx * dx + dy * dy).sqrt();
+        if len == 0. {
+            return;
+        }
+        let half = self.stroke / 2.;
+        let (nx, ny) = (-dy / len * half, dx / len * half);
+        let (x1, y1, x2, y2) = (x1 + 0.5 - ny, y1 + 0.5 + nx, x2 + 0.5 + ny, y2 + 0.5 - nx);
+        self.fill_polygon(&[(x1 + nx, y1 + ny), (x2 + nx, y2 + ny), (x2 - nx, y2 - ny), (x1 - nx, y1 - ny)]);
+    }
+
+    fn outline(&mut self, points: &[(f64, f64)]) {
+        for (i, &(x1, y1)) in points.iter().enumerate() {
+            let (x2, y2) = points[(i + 1) % points.len()];
+            self.line(x1, y1, x2, y2);
+        }
+    }
+
+    fn poly_points(x: i32, y: i32, sides: i32, radius: i32, rotation: i32) -> Vec<(f64, f64)> {
+        (0..sides.clamp(0, 64))
+            .map(|i| {
+                let angle = (rotation as f64 + i as f64 * 360. / sides as f64).to_radians();
+                (x as f64 + radius as f64 * angle.cos(), y as f64 + radius as f64 * angle.sin())
+            })
+            .collect()
+    }
+
+    fn text(&mut self, x: i32, y: i32, align: Align, text: &str) {
+        let lines = text.split('\n').collect::<Vec<_>>();
+        let width = lines.iter().map(|ln| ln.chars().count() as i32).max().unwrap_or(0) * GLYPH_ADVANCE - 1;
+        let height = lines.len() as i32 * LINE_HEIGHT - 1;
+        let left = if align.0 & Align::LEFT != 0 {
+            x
+        } else if align.0 & Align::RIGHT != 0 {
+            x - width
+        } else {
+            x - width / 2
+        };
+        let top = if align.0 & Align::BOTTOM != 0 {
+            y + height
+        } else if align.0 & Align::TOP != 0 {
+            y
+        } else {
+            y + height / 2
+        };
+        for (row, line) in lines.iter().enumerate() {
+            for (col, ch) in line.chars().enumerate() {
+                let gx = left + col as i32 * GLYPH_ADVANCE;
+                let gy = top - row as i32 * LINE_HEIGHT;
+                for (r, bits) in glyph(ch).iter().enumerate() {
+                    for c in 0..GLYPH_WIDTH {
+                        if bits & (1 << (GLYPH_WIDTH - 1 - c)) != 0 {
+                            self.blend(gx + c, gy - r as i32);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn draw(&mut self, command: &DrawCommand) {
+        match *command {
+            DrawCommand::Clear(color) => self.pixels.fill(color),
+            DrawCommand::Color(color) => self.color = color,
+            DrawCommand::Stroke(width) => self.stroke = width.max(0) as f64,
+            DrawCommand::Line(x1, y1, x2, y2) =>
+                self.line(x1 as f64, y1 as f64, x2 as f64, y2 as f64),
+            DrawCommand::Rect(x, y, w, h) => {
+                let (x, y, w, h) = (x as f64, y as f64, w as f64, h as f64);
+                self.fill_polygon(&[(x, y), (x + w, y), (x + w, y + h), (x, y + h)]);
+            },
+            DrawCommand::LineRect(x, y, w, h) => {
+                let (x, y, w, h) = (x as f64, y as f64, w as f64 - 1., h as f64 - 1.);
+                self.outline(&[(x, y), (x + w, y), (x + w, y + h), (x, y + h)]);
+            },
+            DrawCommand::Poly(x, y, sides, radius, rotation) =>
+                self.fill_polygon(&Self::poly_points(x, y, sides, radius, rotation)),
+            DrawCommand::LinePoly(x, y, sides, radius, rotation) =>
+                self.outline(&Self::poly_points(x, y, sides, radius, rotation)),
+            DrawCommand::Triangle(x1, y1, x2, y2, x3, y3) => self.fill_polygon(&[
+                (x1 as f64, y1 as f64), (x2 as f64, y2 as f64), (x3 as f64, y3 as f64)]),
+            DrawCommand::Print(x, y, align, ref text) => self.text(x, y, align, text),
+        }
+    }
+
+    /// Returns the pixels as RGBA bytes, row by row from the top.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 4);
+        for row in self.pixels.chunks(self.size.max(1)).rev() {
+            for color in row {
+                data.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+            }
+        }
+        data
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        encode_png(self.size as u32, self.size as u32, &self.to_rgba())
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Encodes an RGBA image as an uncompressed PNG.
+pub fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(rgba.len() + height as usize);
+    for row in rgba.chunks((width as usize * 4).max(1)) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(if blocks.peek().is_none() { 1 } else { 0 });
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = vec![];
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib);
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framebuffer_shapes() {
+        let red = Color { r: 255, g: 0, b: 0, a: 255 };
+        let mut fb = Framebuffer::new(8);
+        fb.draw(&DrawCommand::Color(red));
+        fb.draw(&DrawCommand::Rect(1, 2, 3, 2));
+        let lit = (0..8).flat_map(|y| (0..8).map(move |x| (x, y)))
+            .filter(|&(x, y)| fb.pixel(x, y) == red)
+            .collect::<Vec<_>>();
+        assert_eq!(lit, [(1, 2), (2, 2), (3, 2), (1, 3), (2, 3), (3, 3)]);
+
+        fb.draw(&DrawCommand::Clear(Framebuffer::BACKGROUND));
+        fb.draw(&DrawCommand::Line(0, 5, 7, 5));
+        assert!((0..8).all(|x| fb.pixel(x, 5) == red && fb.pixel(x, 4) != red && fb.pixel(x, 6) != red));
+
+        fb.draw(&DrawCommand::Color(Color { r: 0, g: 0, b: 255, a: 128 }));
+        fb.draw(&DrawCommand::Rect(0, 0, 8, 8));
+        assert_eq!(fb.pixel(0, 5), Color { r: 127, g: 0, b: 128, a: 255 });
+    }
+
+    #[test]
+    fn test_framebuffer_output() {
+        let mut fb = Framebuffer::new(2);
+        fb.draw(&DrawCommand::Rect(0, 1, 1, 1));
+        assert_eq!(fb.to_rgba(), [255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        let png = fb.to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+    }
+}
@@ -1,12 +1,154 @@
-use std::random::random;
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 use std::str::FromStr;
+use serde::Serialize;
 use strum_macros::EnumString;
 use crate::building::Building;
-use crate::value::Value;
+use crate::color::Color;
+use crate::draw::{Align, DrawBuffer, DrawCommand};
+use crate::rng::Rng;
+use crate::value::{CoercionMode, Value};
 use crate::variable::{VarHandle, Variables};
 use crate::vm::{PrintBuffer, VmError, VmResult};
 
+/// Location of an instruction in the source code.
+/// All values are 1-based, `end_column` is exclusive.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct SourceSpan {
+    pub line: usize,
+    pub start_column: usize,
+    pub end_column: usize,
+}
+
+impl SourceSpan {
+    fn new(line: usize, src: &str, start: usize, end: usize) -> Self {
+        let start_column = src[..start].chars().count() + 1;
+        SourceSpan {
+            line,
+            start_column,
+            end_column: start_column + src[start..end].chars().count(),
+        }
+    }
+}
+
+impl Display for SourceSpan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, columns {}-{}", self.line, self.start_column, self.end_column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    UnknownInstruction(String),
+    MissingArgument(String, usize),
+    InvalidOperator(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    UnterminatedString,
+    InvalidNumber(String),
+}
+
+/// An error in the program source. `line` and `column` are both 1-based.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    /// Creates an error pointing at byte offset `offset` of `src`, which is the text of line `line`.
+    pub fn new(line: usize, src: &str, offset: usize, kind: ParseErrorKind) -> Self {
+        ParseError {
+            line,
+            column: src[..offset].chars().count() + 1,
+            kind,
+        }
+    }
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::UnknownInstruction(name) =>
+                write!(f, "Unknown instruction '{}'", name),
+            ParseErrorKind::MissingArgument(name, idx) =>
+                write!(f, "Missing argument {} of instruction '{}'", idx, name),
+            ParseErrorKind::InvalidOperator(op) =>
+                write!(f, "Invalid operator '{}'", op),
+            ParseErrorKind::UndefinedLabel(label) =>
+                write!(f, "Undefined label '{}'", label),
+            ParseErrorKind::DuplicateLabel(label) =>
+                write!(f, "Duplicate label '{}'", label),
+            ParseErrorKind::UnterminatedString =>
+                write!(f, "Unterminated string"),
+            ParseErrorKind::InvalidNumber(literal) =>
+                write!(f, "Invalid or out of range number '{}'", literal),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Parse error at line {}, column {}: {}", self.line, self.column, self.kind)
+    }
+}
+
+/// Parses a numeric literal: decimal numbers with an optional exponent,
+/// `0x` hexadecimal and `0b` binary integers, `%rrggbb[aa]` colors and `%[name]` named colors.
+/// Like in the game, hexadecimal and binary literals cannot be signed.
+pub fn parse_number(string: &str) -> Option<f64> {
+    if let Some(name) = string.strip_prefix("%[").and_then(|s| s.strip_suffix(']')) {
+        return Color::named(name).map(Color::to_double_bits);
+    }
+    if let Some(hex) = string.strip_prefix('%') {
+        return Color::parse_hex(hex).map(Color::to_double_bits);
+    }
+    for (prefix, radix) in [("0x", 16), ("0b", 2)] {
+        if let Some(digits) = string.strip_prefix(prefix) {
+            if digits.starts_with(['+', '-']) {
+                return None;
+            }
+            return i64::from_str_radix(digits, radix).ok().map(|num| num as f64);
+        }
+    }
+    let (negative, unsigned) = match string.as_bytes().first() {
+        Some(b'-') => (true, &string[1..]),
+        Some(b'+') => (false, &string[1..]),
+        _ => (false, string),
+    };
+    parse_decimal(unsigned).map(|num| if negative { -num } else { num })
+}
+
+/// Whether a token that is not a valid number was meant as a hexadecimal or binary literal,
+/// such as `0xfffffffffffffffff`, `0b102` or `-0xff`, rather than a variable name.
+fn is_malformed_number(string: &str) -> bool {
+    let unsigned = string.strip_prefix(['-', '+']).unwrap_or(string);
+    unsigned.starts_with("0x") || unsigned.starts_with("0b")
+}
+
+/// Parses an unsigned decimal number, such as `1`, `.5`, `2.`, `1e5` or `3.5E-2`.
+/// Unlike `f64::from_str`, `inf` and `nan` are not accepted.
+fn parse_decimal(string: &str) -> Option<f64> {
+    let (mantissa, exponent) = match string.find(['e', 'E']) {
+        Some(idx) => (&string[..idx], Some(&string[idx + 1..])),
+        None => (string, None),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if whole.is_empty() && fraction.is_empty()
+        || !whole.bytes().chain(fraction.bytes()).all(|ch| ch.is_ascii_digit()) {
+        return None;
+    }
+    if let Some(exponent) = exponent {
+        let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        if digits.is_empty() || !digits.bytes().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+    }
+    f64::from_str(string).ok()
+}
+
 #[derive(Debug)]
 pub enum ValueArg {
     Value(Value),
@@ -14,14 +156,17 @@ pub enum ValueArg {
 }
 
 impl ValueArg {
-    fn parse(string: &str, vars: &mut Variables) -> Self {
-        if string.starts_with("\"") && string.ends_with("\"") {
-            ValueArg::Value(Value::Str(Rc::new(string[1..string.len()-1].into())))
-        } else if let Ok(num) = f64::from_str(string) {
+    fn parse(string: &str, vars: &mut Variables) -> Result<Self, ParseErrorKind> {
+        Ok(if let Some(quoted) = string.strip_prefix('"') {
+            let quoted = quoted.strip_suffix('"').ok_or(ParseErrorKind::UnterminatedString)?;
+            ValueArg::Value(Value::Str(Arc::new(quoted.into())))
+        } else if let Some(num) = parse_number(string) {
             ValueArg::Value(Value::Num(num))
+        } else if is_malformed_number(string) {
+            return Err(ParseErrorKind::InvalidNumber(string.to_string()));
         } else {
             ValueArg::Variable(vars.handle(string))
-        }
+        })
     }
 
     pub fn eval(&self, vars: &Variables) -> VmResult<Value> {
@@ -32,9 +177,71 @@ impl ValueArg {
     }
 }
 
+/// State of the processor an instruction is executed on.
+pub struct ExecuteContext<'a> {
+    pub vars: &'a Variables,
+    pub print_buffer: &'a PrintBuffer,
+    pub draw_buffer: &'a DrawBuffer,
+    pub buildings: &'a [Arc<dyn Building>],
+    pub pc: VarHandle,
+    pub coercion: CoercionMode,
+    pub rng: &'a Rng,
+}
+
+impl ExecuteContext<'_> {
+    fn num(&self, arg: &ValueArg) -> VmResult<f64> {
+        let value = arg.eval(self.vars)?;
+        match self.coercion {
+            CoercionMode::Strict => value.as_num(),
+            CoercionMode::Lenient => Ok(value.to_num_lenient()),
+        }
+    }
+
+    fn int(&self, arg: &ValueArg) -> VmResult<i64> {
+        match self.coercion {
+            CoercionMode::Strict => arg.eval(self.vars)?.as_int(),
+            CoercionMode::Lenient => Ok(self.num(arg)? as i64),
+        }
+    }
+
+    fn equal(&self, a: &Value, b: &Value) -> bool {
+        match self.coercion {
+            CoercionMode::Strict => a == b,
+            CoercionMode::Lenient => a.lenient_eq(b),
+        }
+    }
+
+    /// Evaluates an index argument. In lenient mode, anything but a string is converted to a number
+    /// and truncated like the game does.
+    fn index(&self, arg: &ValueArg) -> VmResult<Value> {
+        let value = arg.eval(self.vars)?;
+        Ok(match (self.coercion, &value) {
+            (CoercionMode::Lenient, Value::Str(_)) | (CoercionMode::Strict, _) => value,
+            (CoercionMode::Lenient, _) => Value::Num(value.to_num_lenient().trunc()),
+        })
+    }
+}
+
+/// A value stored into a building by `write`.
+#[derive(Debug, Clone)]
+pub struct CellWrite {
+    pub building: Arc<dyn Building>,
+    pub index: Value,
+    /// Previous value of the cell, if the building keeps it.
+    pub old: Option<Value>,
+    /// The value passed to the building, after coercion.
+    pub value: Value,
+}
+
 #[derive(Debug)]
 pub struct InstructionExecuteResult {
     pub halt: bool,
+    /// Number of seconds to suspend the processor for.
+    pub wait: Option<f64>,
+    /// A `jump` instruction was taken.
+    pub jumped: bool,
+    /// A `write` instruction stored a value.
+    pub written: Option<CellWrite>,
 }
 
 #[derive(Debug, EnumString)]
@@ -90,6 +297,8 @@ pub enum Instruction {
     PrintChar(ValueArg),
     Format(ValueArg),
 
+    Draw(String, ValueArg, ValueArg, ValueArg, ValueArg, ValueArg, ValueArg),
+    DrawFlush(ValueArg),
     PrintFlush(ValueArg),
     GetLink(VarHandle, ValueArg),
     Sensor(VarHandle, ValueArg, ValueArg),
@@ -104,169 +313,408 @@ pub enum Instruction {
 }
 
 macro_rules! arg {
-    (out, $vars:expr, $arg:expr) => ($vars.handle($arg));
-    (in, $vars:expr, $arg:expr) => (ValueArg::parse($arg, $vars));
-    (imm, $vars:expr, $arg:expr) => (String::from($arg));
-    (op, $vars:expr, $arg:expr) => (Operator::from_str($arg).unwrap());
+    (out, $p:expr, $i:expr) => ($p.out($i)?);
+    (in, $p:expr, $i:expr) => ($p.input($i)?);
+    (opt, $p:expr, $i:expr) => ($p.optional_input($i)?);
+    (align, $p:expr, $i:expr) => ($p.align($i)?);
+    (imm, $p:expr, $i:expr) => ($p.imm($i)?);
+    (op, $p:expr, $i:expr) => ($p.op($i)?);
+    (label, $p:expr, $i:expr) => ($p.label($i)?);
 }
 
 macro_rules! ins {
-    ($ins:ident, $vars:expr, $args:expr) => {
+    ($ins:ident, $p:expr) => {
         Instruction::$ins
     };
-    ($ins:ident, $vars:expr, $args:expr => $($sel:tt $i:expr),*) => {
-        Instruction::$ins($(arg!($sel, $vars, $args[$i])),*)
+    ($ins:ident, $p:expr => $($sel:tt $i:expr),*) => {
+        Instruction::$ins($(arg!($sel, $p, $i)),*)
     };
 }
 
+/// Parsing context of a single line.
+struct LineParser<'a, 'v> {
+    line: &'a str,
+    line_num: usize,
+    tokens: Vec<(usize, &'a str)>,
+    labels: &'a HashMap<String, usize>,
+    vars: &'v mut Variables,
+}
+
+impl<'a> LineParser<'a, '_> {
+    fn err(&self, offset: usize, kind: ParseErrorKind) -> ParseError {
+        ParseError::new(self.line_num, self.line, offset, kind)
+    }
+
+    fn token(&self, i: usize) -> Result<(usize, &'a str), ParseError> {
+        self.tokens.get(i).copied().ok_or_else(|| self.err(
+            self.line.trim_end().len(), ParseErrorKind::MissingArgument(self.tokens[0].1.to_string(), i)))
+    }
+
+    fn out(&mut self, i: usize) -> Result<VarHandle, ParseError> {
+        let (_, name) = self.token(i)?;
+        Ok(self.vars.handle(name))
+    }
+
+    fn input(&mut self, i: usize) -> Result<ValueArg, ParseError> {
+        let (offset, string) = self.token(i)?;
+        ValueArg::parse(string, self.vars).map_err(|kind| self.err(offset, kind))
+    }
+
+    /// Like `input`, but a missing argument evaluates to `null`.
+    fn optional_input(&mut self, i: usize) -> Result<ValueArg, ParseError> {
+        if i < self.tokens.len() {
+            self.input(i)
+        } else {
+            Ok(ValueArg::Value(Value::Null))
+        }
+    }
+
+    /// Like `input`, but a missing argument evaluates to `default`.
+    fn input_or(&mut self, i: usize, default: f64) -> Result<ValueArg, ParseError> {
+        if i < self.tokens.len() {
+            self.input(i)
+        } else {
+            Ok(ValueArg::Value(Value::Num(default)))
+        }
+    }
+
+    /// Optional text alignment, given either by name or as a value, 0 if missing.
+    fn align(&mut self, i: usize) -> Result<ValueArg, ParseError> {
+        match self.tokens.get(i).and_then(|(_, name)| Align::from_name(name)) {
+            Some(align) => Ok(ValueArg::Value(Value::Num(align.0 as f64))),
+            None => self.input_or(i, 0.),
+        }
+    }
+
+    fn imm(&self, i: usize) -> Result<String, ParseError> {
+        Ok(self.token(i)?.1.to_string())
+    }
+
+    fn op(&self, i: usize) -> Result<Operator, ParseError> {
+        let (offset, op) = self.token(i)?;
+        Operator::from_str(op).map_err(|_| self.err(offset, ParseErrorKind::InvalidOperator(op.to_string())))
+    }
+
+    fn label(&self, i: usize) -> Result<ValueArg, ParseError> {
+        let (offset, target) = self.token(i)?;
+        if let Some(idx) = self.labels.get(target) {
+            Ok(ValueArg::Value(Value::Num(*idx as f64)))
+        } else if let Some(num) = parse_number(target) {
+            Ok(ValueArg::Value(Value::Num(num)))
+        } else {
+            Err(self.err(offset, ParseErrorKind::UndefinedLabel(target.to_string())))
+        }
+    }
+}
+
 macro_rules! two_nums {
-    ($vars:ident, $a:ident, $b:ident) => {
-        ($a.eval($vars)?.as_num()?, $b.eval($vars)?.as_num()?)
+    ($ctx:ident, $a:ident, $b:ident) => {
+        ($ctx.num($a)?, $ctx.num($b)?)
     };
 }
 
 macro_rules! binary {
-    ($vars:ident, $a:ident, $b:ident, fn $func:expr) => {
+    ($ctx:ident, $a:ident, $b:ident, fn $func:expr) => {
         {
-            let (a, b) = two_nums!($vars, $a, $b);
+            let (a, b) = two_nums!($ctx, $a, $b);
             Value::Num($func(a, b))
         }
     };
-    ($vars:ident, $a:ident, $b:ident, ?fn $func:expr) => {
+    ($ctx:ident, $a:ident, $b:ident, ?fn $func:expr) => {
         {
-            let (a, b) = two_nums!($vars, $a, $b);
+            let (a, b) = two_nums!($ctx, $a, $b);
             Value::Num($func(a, b)?)
         }
     };
-    ($vars:ident, $a:ident, $b:ident, $op:tt) => {
+    ($ctx:ident, $a:ident, $b:ident, $op:tt) => {
         {
-            let (a, b) = two_nums!($vars, $a, $b);
+            let (a, b) = two_nums!($ctx, $a, $b);
             Value::Num(a $op b)
         }
     };
-    ($vars:ident, $a:ident, $b:ident, !fn $func:expr) => {
+    ($ctx:ident, $a:ident, $b:ident, !fn $func:expr) => {
         {
-            let (a, b) = two_nums!($vars, $a, $b);
+            let (a, b) = two_nums!($ctx, $a, $b);
             Value::Num(if $func(a, b) { 1. } else { 0. })
         }
     }
 }
 
 macro_rules! binary_i {
-    ($vars:ident, $a:ident, $b:ident, fn $func:expr) => {
+    ($ctx:ident, $a:ident, $b:ident, fn $func:expr) => {
         {
-            let (a, b) = two_nums!($vars, $a, $b);
+            let (a, b) = two_nums!($ctx, $a, $b);
             Value::Num($func(a as i64, b as i64) as f64)
         }
     };
-    ($vars:ident, $a:ident, $b:ident, ?fn $func:expr) => {
+    ($ctx:ident, $a:ident, $b:ident, ?fn $func:expr) => {
         {
-            let (a, b) = two_nums!($vars, $a, $b);
+            let (a, b) = two_nums!($ctx, $a, $b);
             Value::Num($func(a as i64, b as i64)? as f64)
         }
     };
-    ($vars:ident, $a:ident, $b:ident, $op:tt) => {
+    ($ctx:ident, $a:ident, $b:ident, $op:tt) => {
         {
-            let (a, b) = two_nums!($vars, $a, $b);
+            let (a, b) = two_nums!($ctx, $a, $b);
             Value::Num(((a as i64) $op (b as i64)) as f64)
         }
     };
 }
 
 macro_rules! unary {
-    ($vars:ident, $a:ident, fn $func:expr) => {
+    ($ctx:ident, $a:ident, fn $func:expr) => {
         {
-            Value::Num($func($a.eval($vars)?.as_num()?))
+            Value::Num($func($ctx.num($a)?))
         }
     };
-    ($vars:ident, $a:ident, $op:tt) => {
+    ($ctx:ident, $a:ident, $op:tt) => {
         {
-            Value::Num($op $a.eval($vars)?.as_num()?)
+            Value::Num($op $ctx.num($a)?)
         }
     };
 }
 
 impl Instruction {
-    fn split_line(line: &str) -> Vec<&str> {
-        let line = line.trim();
+    /// Splits a line into whitespace-separated tokens, keeping quoted strings together
+    /// and dropping `#` comments. Each token is returned along with its byte offset in the line.
+    fn split_line(line: &str) -> Vec<(usize, &str)> {
         let mut segments = vec![];
-        let mut start = 0;
-        let mut next_start = false;
-        let mut last_ch = 0;
+        let mut start = None;
         let mut quotes = false;
+        let mut end = line.len();
         for (i, ch) in line.char_indices() {
-            if ch == ' ' && !quotes {
-                segments.push(&line[start..i]);
-                next_start = true;
-            } else if next_start {
-                next_start = false;
-                start = i;
-            }
-            if ch == '"' {
-                quotes = !quotes;
+            if ch == '#' && !quotes {
+                end = i;
+                break;
+            } else if ch.is_whitespace() && !quotes {
+                if let Some(start) = start.take() {
+                    segments.push((start, &line[start..i]));
+                }
+            } else {
+                start.get_or_insert(i);
+                if ch == '"' {
+                    quotes = !quotes;
+                }
             }
-            last_ch = i + ch.len_utf8();
         }
-        if start != last_ch {
-            segments.push(&line[start..last_ch]);
+        if let Some(start) = start {
+            segments.push((start, &line[start..end]));
         }
         segments
     }
 
-    pub fn parse(line: &str, vars: &mut Variables) -> Option<Self> {
-        let args = Self::split_line(line);
-        if args.is_empty() {
-            return None;
+    /// Returns the name of the label defined by a line, if it is a label line.
+    fn label_name<'a>(tokens: &[(usize, &'a str)]) -> Option<&'a str> {
+        match tokens {
+            [(_, token)] if token.len() > 1 && token.ends_with(':') && !token.starts_with('"') =>
+                Some(&token[..token.len() - 1]),
+            _ => None,
+        }
+    }
+
+    /// Parses a whole program, returning each instruction along with its location.
+    /// Labels are resolved to instruction indices,
+    /// a label at the end of the program points to the first instruction.
+    pub fn parse_program(code: &str, vars: &mut Variables) -> Result<Vec<(Self, SourceSpan)>, ParseError> {
+        let mut labels = HashMap::new();
+        let mut count = 0;
+        let mut trailing = vec![];
+        for (i, line) in code.split('\n').enumerate() {
+            let tokens = Self::split_line(line);
+            if let Some(label) = Self::label_name(&tokens) {
+                if labels.insert(label.to_string(), count).is_some() {
+                    return Err(ParseError::new(
+                        i + 1, line, tokens[0].0, ParseErrorKind::DuplicateLabel(label.to_string())));
+                }
+                trailing.push(label);
+            } else if !tokens.is_empty() {
+                count += 1;
+                trailing.clear();
+            }
+        }
+        for label in trailing {
+            labels.insert(label.to_string(), 0);
         }
-        Some(match args[0] {
-            "read" => ins!(Read, vars, args => out 1, in 2, in 3),
-            "write" => ins!(Write, vars, args => in 1, in 2, in 3),
-            "print" => ins!(Print, vars, args => in 1),
-            "printchar" => ins!(PrintChar, vars, args => in 1),
-            "format" => ins!(Format, vars, args => in 1),
 
-            "printflush" => ins!(PrintFlush, vars, args => in 1),
-            "getlink" => ins!(GetLink, vars, args => out 1, in 2),
-            "sensor" => ins!(Sensor, vars, args => out 1, in 2, in 3),
+        let mut instructions = vec![];
+        for (i, line) in code.split('\n').enumerate() {
+            if let Some(ins) = Self::parse(line, i + 1, &labels, vars)? {
+                instructions.push(ins);
+            }
+        }
+        Ok(instructions)
+    }
 
-            "set" => ins!(Set, vars, args => out 1, in 2),
-            "op" => ins!(Op, vars, args => op 1, out 2, in 3, in 4),
+    /// Parses a single line of code. `line_num` is the 1-based line number used for errors.
+    /// Returns `None` if the line contains no instruction.
+    pub fn parse(line: &str, line_num: usize, labels: &HashMap<String, usize>,
+                 vars: &mut Variables) -> Result<Option<(Self, SourceSpan)>, ParseError> {
+        let tokens = Self::split_line(line);
+        let (Some(&(name_offset, name)), Some(&(last_offset, last))) = (tokens.first(), tokens.last()) else {
+            return Ok(None);
+        };
+        if Self::label_name(&tokens).is_some() {
+            return Ok(None);
+        }
+        let span = SourceSpan::new(line_num, line, name_offset, last_offset + last.len());
+        let mut p = LineParser {
+            line,
+            line_num,
+            tokens,
+            labels,
+            vars,
+        };
+        Ok(Some((match name {
+            "read" => ins!(Read, p => out 1, in 2, in 3),
+            "write" => ins!(Write, p => in 1, in 2, in 3),
+            "print" => ins!(Print, p => in 1),
+            "printchar" => ins!(PrintChar, p => in 1),
+            "format" => ins!(Format, p => in 1),
+
+            "draw" => {
+                // like in the game, missing arguments are 0, except the alpha of `color` which is opaque
+                let op = p.imm(1)?;
+                let c = if op == "print" { p.align(4)? } else { p.input_or(4, 0.)? };
+                let d = p.input_or(5, if op == "color" { 255. } else { 0. })?;
+                Instruction::Draw(op, p.input_or(2, 0.)?, p.input_or(3, 0.)?, c, d,
+                                  p.input_or(6, 0.)?, p.input_or(7, 0.)?)
+            },
+            "drawflush" => ins!(DrawFlush, p => in 1),
+            "printflush" => ins!(PrintFlush, p => in 1),
+            "getlink" => ins!(GetLink, p => out 1, in 2),
+            "sensor" => ins!(Sensor, p => out 1, in 2, in 3),
 
-            "wait" => ins!(Wait, vars, args => in 1),
-            "stop" => ins!(Stop, vars, args),
-            "end" => ins!(End, vars, args),
-            "jump" => ins!(Jump, vars, args => in 1, imm 2, in 3, in 4),
+            "set" => ins!(Set, p => out 1, in 2),
+            "op" => ins!(Op, p => op 1, out 2, in 3, in 4),
 
-            name => panic!("Unsupported instruction: '{}'", name),
-        })
+            "wait" => ins!(Wait, p => in 1),
+            "stop" => ins!(Stop, p),
+            "end" => ins!(End, p),
+            "jump" => ins!(Jump, p => label 1, imm 2, opt 3, opt 4),
+
+            name => return Err(p.err(name_offset, ParseErrorKind::UnknownInstruction(name.to_string()))),
+        }, span)))
+    }
+
+    /// Name of the instruction as written in the source code.
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            Instruction::Read(..) => "read",
+            Instruction::Write(..) => "write",
+            Instruction::Print(..) => "print",
+            Instruction::PrintChar(..) => "printchar",
+            Instruction::Format(..) => "format",
+            Instruction::Draw(..) => "draw",
+            Instruction::DrawFlush(..) => "drawflush",
+            Instruction::PrintFlush(..) => "printflush",
+            Instruction::GetLink(..) => "getlink",
+            Instruction::Sensor(..) => "sensor",
+            Instruction::Set(..) => "set",
+            Instruction::Op(..) => "op",
+            Instruction::Wait(..) => "wait",
+            Instruction::Stop => "stop",
+            Instruction::End => "end",
+            Instruction::Jump(..) => "jump",
+        }
+    }
+
+    pub fn is_conditional_jump(&self) -> bool {
+        matches!(self, Instruction::Jump(_, op, ..) if op != "always")
+    }
+
+    /// Operands read by the instruction, in source order.
+    pub fn inputs(&self) -> Vec<&ValueArg> {
+        match self {
+            Instruction::Read(_, a, b) | Instruction::Sensor(_, a, b) | Instruction::Op(_, _, a, b) =>
+                vec![a, b],
+            Instruction::Write(a, b, c) => vec![a, b, c],
+            Instruction::Print(a) | Instruction::PrintChar(a) | Instruction::Format(a)
+            | Instruction::DrawFlush(a) | Instruction::PrintFlush(a) | Instruction::GetLink(_, a)
+            | Instruction::Set(_, a) | Instruction::Wait(a) => vec![a],
+            Instruction::Draw(_, a, b, c, d, e, f) => vec![a, b, c, d, e, f],
+            Instruction::Jump(_, _, a, b) => vec![a, b],
+            Instruction::Stop | Instruction::End => vec![],
+        }
     }
 
-    pub fn execute(&self, vars: &Variables, print_buffer: &PrintBuffer,
-                   buildings: &[Rc<dyn Building>], pc: VarHandle) -> VmResult<InstructionExecuteResult> {
+    /// Variable written by the instruction, if any.
+    pub fn output(&self) -> Option<VarHandle> {
+        match self {
+            Instruction::Read(dst, ..) | Instruction::GetLink(dst, _) | Instruction::Sensor(dst, ..)
+            | Instruction::Set(dst, _) | Instruction::Op(_, dst, ..) => Some(*dst),
+            _ => None,
+        }
+    }
+
+    pub fn execute(&self, ctx: &ExecuteContext) -> VmResult<InstructionExecuteResult> {
+        let &ExecuteContext { vars, print_buffer, draw_buffer, buildings, pc, .. } = ctx;
         match self {
             Instruction::Read(dst, src, idx) => {
                 let src = src.eval(vars)?;
-                let idx = idx.eval(vars)?;
+                let idx = ctx.index(idx)?;
                 dst.set(vars, if let Ok(string) = src.as_str() {
                     Value::Num(idx.do_index_copy(string.as_utf_16(), "string")? as f64)
                 } else {
-                    src.as_building()?.read(idx)?
+                    src.as_building()?.read_as(vars, idx)?
                 })?
             },
-            Instruction::Write(src, dst, idx) =>
-                dst.eval(vars)?.as_building()?.write(idx.eval(vars)?, src.eval(vars)?)?,
+            Instruction::Write(src, dst, idx) => {
+                let building = dst.eval(vars)?.as_building()?;
+                let idx = ctx.index(idx)?;
+                // numeric indices address memory, which only stores numbers
+                let src = match (ctx.coercion, &idx) {
+                    (CoercionMode::Lenient, Value::Num(_)) => Value::Num(ctx.num(src)?),
+                    _ => src.eval(vars)?,
+                };
+                let old = building.write_as(vars, idx.clone(), src.clone())?;
+                return Ok(InstructionExecuteResult {
+                    halt: false,
+                    wait: None,
+                    jumped: false,
+                    written: Some(CellWrite {
+                        building,
+                        index: idx,
+                        old,
+                        value: src,
+                    }),
+                });
+            },
             Instruction::Print(val) =>
                 print_buffer.write(&val.eval(vars)?.to_string()),
             Instruction::PrintChar(val) =>
-                print_buffer.write_utf_16(val.eval(vars)?.as_int()? as u16)?,
+                print_buffer.write_utf_16(ctx.int(val)? as u16)?,
             Instruction::Format(val) =>
                 print_buffer.format(&val.eval(vars)?.to_string())?,
 
+            Instruction::Draw(op, a, b, c, d, e, f) => {
+                let int = |arg: &ValueArg| -> VmResult<i32> { Ok(ctx.num(arg)? as i32) };
+                let channel = |arg: &ValueArg| -> VmResult<u8> { Ok(int(arg)?.clamp(0, 255) as u8) };
+                draw_buffer.push(match op.as_str() {
+                    "clear" => DrawCommand::Clear(
+                        Color { r: channel(a)?, g: channel(b)?, b: channel(c)?, a: 255 }),
+                    "color" => DrawCommand::Color(
+                        Color { r: channel(a)?, g: channel(b)?, b: channel(c)?, a: channel(d)? }),
+                    "col" => DrawCommand::Color(Color::from_double_bits(ctx.num(a)?)),
+                    "stroke" => DrawCommand::Stroke(int(a)?),
+                    "line" => DrawCommand::Line(int(a)?, int(b)?, int(c)?, int(d)?),
+                    "rect" => DrawCommand::Rect(int(a)?, int(b)?, int(c)?, int(d)?),
+                    "lineRect" => DrawCommand::LineRect(int(a)?, int(b)?, int(c)?, int(d)?),
+                    "poly" => DrawCommand::Poly(int(a)?, int(b)?, int(c)?, int(d)?, int(e)?),
+                    "linePoly" => DrawCommand::LinePoly(int(a)?, int(b)?, int(c)?, int(d)?, int(e)?),
+                    "triangle" => DrawCommand::Triangle(
+                        int(a)?, int(b)?, int(c)?, int(d)?, int(e)?, int(f)?),
+                    "print" => DrawCommand::Print(
+                        int(a)?, int(b)?, Align(int(c)? as u8), print_buffer.take()),
+                    op => return Err(VmError::InvalidOperation(op.to_string())),
+                })
+            },
+            Instruction::DrawFlush(val) =>
+                val.eval(vars)?.as_building()?.draw_flush(draw_buffer.take())?,
             Instruction::PrintFlush(val) =>
                 val.eval(vars)?.as_building()?.print_flush(print_buffer.take())?,
             Instruction::GetLink(dst, idx) =>
                 dst.set(vars, Value::Building(
-                    idx.eval(vars)?.do_index(buildings, "get link")?.clone()))?,
+                    ctx.index(idx)?.do_index(buildings, "get link")?.clone()))?,
             Instruction::Sensor(dst, src, prop) =>
                 dst.set(vars, src.eval(vars)?.sense(prop.eval(vars)?.as_property()?)?)?,
 
@@ -274,76 +722,80 @@ impl Instruction {
                 dst.set(vars, src.eval(vars)?)?,
             Instruction::Op(op, dst, a, b) =>
                 dst.set(vars, match op {
-                    Operator::Add => binary!(vars, a, b, +),
-                    Operator::Sub => binary!(vars, a, b, -),
-                    Operator::Mul => binary!(vars, a, b, *),
-                    Operator::Div => binary!(vars, a, b, /),
-                    Operator::Idiv => binary_i!(vars, a, b,
+                    Operator::Add => binary!(ctx, a, b, +),
+                    Operator::Sub => binary!(ctx, a, b, -),
+                    Operator::Mul => binary!(ctx, a, b, *),
+                    Operator::Div => binary!(ctx, a, b, /),
+                    Operator::Idiv => binary_i!(ctx, a, b,
                         ?fn |a: i64, b: i64| Ok(
                             a
                             .checked_div(b)
                             .ok_or(VmError::DivisionByZero)? as f64
                         )),
-                    Operator::Mod => binary!(vars, a, b, %),
-                    Operator::Pow => binary!(vars, a, b, fn |a, b| f64::powf(a, b)),
-                    Operator::Not => unary!(vars, a,
+                    Operator::Mod => binary!(ctx, a, b, %),
+                    Operator::Pow => binary!(ctx, a, b, fn f64::powf),
+                    Operator::Not => unary!(ctx, a,
                         fn |a: f64| if a.abs() < f64::EPSILON { 1. } else { 0. }),
-                    Operator::Land => binary!(vars, a, b,
+                    Operator::Land => binary!(ctx, a, b,
                         !fn |a: f64, b: f64| a.abs() > f64::EPSILON && b.abs() > f64::EPSILON),
-                    Operator::LessThan => binary!(vars, a, b, !fn |a: f64, b: f64| a < b),
-                    Operator::LessThanEq => binary!(vars, a, b, !fn |a: f64, b: f64| a <= b),
-                    Operator::GreaterThan => binary!(vars, a, b, !fn |a: f64, b: f64| a > b),
-                    Operator::GreaterThanEq => binary!(vars, a, b, !fn |a: f64, b: f64| a >= b),
-                    Operator::StrictEqual | Operator::Equal =>
+                    Operator::LessThan => binary!(ctx, a, b, !fn |a: f64, b: f64| a < b),
+                    Operator::LessThanEq => binary!(ctx, a, b, !fn |a: f64, b: f64| a <= b),
+                    Operator::GreaterThan => binary!(ctx, a, b, !fn |a: f64, b: f64| a > b),
+                    Operator::GreaterThanEq => binary!(ctx, a, b, !fn |a: f64, b: f64| a >= b),
+                    Operator::StrictEqual =>
                         Value::Num(if a.eval(vars)? == b.eval(vars)? { 1. } else { 0. }),
+                    Operator::Equal =>
+                        Value::Num(if ctx.equal(&a.eval(vars)?, &b.eval(vars)?) { 1. } else { 0. }),
                     Operator::NotEqual =>
-                        Value::Num(if a.eval(vars)? != b.eval(vars)? { 1. } else { 0. }),
-                    Operator::Shl => binary_i!(vars, a, b, <<),
-                    Operator::Shr => binary_i!(vars, a, b, >>),
-                    Operator::Or => binary_i!(vars, a, b, |),
-                    Operator::And => binary_i!(vars, a, b, &),
-                    Operator::Xor => binary_i!(vars, a, b, ^),
-                    Operator::Flip => unary!(vars, a, fn |a: f64| !(a as i64) as f64),
-                    Operator::Max => binary!(vars, a, b, fn f64::max),
-                    Operator::Min => binary!(vars, a, b, fn f64::min),
-                    Operator::Abs => unary!(vars, a, fn f64::abs),
-                    Operator::Log => unary!(vars, a, fn f64::ln),
-                    Operator::Log10 => unary!(vars, a, fn f64::log10),
-                    Operator::Floor => unary!(vars, a, fn f64::floor),
-                    Operator::Ceil => unary!(vars, a, fn f64::ceil),
-                    Operator::Sqrt => unary!(vars, a, fn f64::sqrt),
-                    Operator::Angle => binary!(vars, a, b, fn |a: f64, b: f64| f64::atan2(b, a).to_degrees()),
-                    Operator::Length => binary!(vars, a, b, fn |a: f64, b: f64| (a * a + b * b).sqrt()),
-                    Operator::Sin => unary!(vars, a, fn |a: f64| a.to_radians().sin()),
-                    Operator::Cos => unary!(vars, a, fn |a: f64| a.to_radians().cos()),
-                    Operator::Tan => unary!(vars, a, fn |a: f64| a.to_radians().tan()),
-                    Operator::Asin => unary!(vars, a, fn |a: f64| a.asin().to_degrees()),
-                    Operator::Acos => unary!(vars, a, fn |a: f64| a.acos().to_degrees()),
-                    Operator::Atan => unary!(vars, a, fn |a: f64| a.atan().to_degrees()),
-                    Operator::Rand => unary!(vars, a,
-                        fn |a: f64| random::<u32>() as f64 / u32::MAX as f64 * a),
-                    Operator::Sign => unary!(vars, a,
+                        Value::Num(if ctx.equal(&a.eval(vars)?, &b.eval(vars)?) { 0. } else { 1. }),
+                    Operator::Shl => binary_i!(ctx, a, b, <<),
+                    Operator::Shr => binary_i!(ctx, a, b, >>),
+                    Operator::Or => binary_i!(ctx, a, b, |),
+                    Operator::And => binary_i!(ctx, a, b, &),
+                    Operator::Xor => binary_i!(ctx, a, b, ^),
+                    Operator::Flip => unary!(ctx, a, fn |a: f64| !(a as i64) as f64),
+                    Operator::Max => binary!(ctx, a, b, fn f64::max),
+                    Operator::Min => binary!(ctx, a, b, fn f64::min),
+                    Operator::Abs => unary!(ctx, a, fn f64::abs),
+                    Operator::Log => unary!(ctx, a, fn f64::ln),
+                    Operator::Log10 => unary!(ctx, a, fn f64::log10),
+                    Operator::Floor => unary!(ctx, a, fn f64::floor),
+                    Operator::Ceil => unary!(ctx, a, fn f64::ceil),
+                    Operator::Sqrt => unary!(ctx, a, fn f64::sqrt),
+                    Operator::Angle => binary!(ctx, a, b, fn |a: f64, b: f64| f64::atan2(b, a).to_degrees()),
+                    Operator::Length => binary!(ctx, a, b, fn |a: f64, b: f64| (a * a + b * b).sqrt()),
+                    Operator::Sin => unary!(ctx, a, fn |a: f64| a.to_radians().sin()),
+                    Operator::Cos => unary!(ctx, a, fn |a: f64| a.to_radians().cos()),
+                    Operator::Tan => unary!(ctx, a, fn |a: f64| a.to_radians().tan()),
+                    Operator::Asin => unary!(ctx, a, fn |a: f64| a.asin().to_degrees()),
+                    Operator::Acos => unary!(ctx, a, fn |a: f64| a.acos().to_degrees()),
+                    Operator::Atan => unary!(ctx, a, fn |a: f64| a.atan().to_degrees()),
+                    Operator::Rand => unary!(ctx, a, fn |a: f64| ctx.rng.next_f64() * a),
+                    Operator::Sign => unary!(ctx, a,
                         fn |a: f64| if a > 0. { 1. } else if a < 0. { -1. } else { 0. }),
                 })?,
 
-            Instruction::Wait(time) => {
-                // only checks if parameter is a number
-                time.eval(vars)?.as_num()?;
-            },
+            Instruction::Wait(time) => return Ok(InstructionExecuteResult {
+                halt: false,
+                wait: Some(ctx.num(time)?),
+                jumped: false,
+                written: None,
+            }),
             Instruction::Stop => return Ok(InstructionExecuteResult {
-                halt: true
+                halt: true,
+                wait: None,
+                jumped: false,
+                written: None,
             }),
             Instruction::End => pc.set(vars, Value::Num(0.))?,
             Instruction::Jump(dst, op, a, b) =>
                 if op == "always" || {
-                    let a = a.eval(vars)?;
-                    let b = b.eval(vars)?;
                     match op.as_str() {
-                        "equal" | "strictEqual" => a == b,
-                        "notEqual" => a != b,
+                        "strictEqual" => a.eval(vars)? == b.eval(vars)?,
+                        "equal" => ctx.equal(&a.eval(vars)?, &b.eval(vars)?),
+                        "notEqual" => !ctx.equal(&a.eval(vars)?, &b.eval(vars)?),
                         op => {
-                            let a = a.as_num()?;
-                            let b = b.as_num()?;
+                            let (a, b) = two_nums!(ctx, a, b);
                             match op {
                                 "lessThan" => a < b,
                                 "lessThanEq" => a <= b,
@@ -354,19 +806,123 @@ impl Instruction {
                         },
                     }
                 } {
-                    pc.set(vars, dst.eval(vars)?)?
+                    pc.set(vars, dst.eval(vars)?)?;
+                    return Ok(InstructionExecuteResult {
+                        halt: false,
+                        wait: None,
+                        jumped: true,
+                        written: None,
+                    });
                 }
         }
         Ok(InstructionExecuteResult {
             halt: false,
+            wait: None,
+            jumped: false,
+            written: None,
         })
     }
 }
 
-#[test]
-fn test_instruction_split_line() {
-    assert_eq!(Instruction::split_line("a b c"), ["a", "b", "c"]);
-    assert_eq!(Instruction::split_line("a \"b c d\" ef g"), ["a", "\"b c d\"", "ef", "g"]);
-    assert_eq!(Instruction::split_line("va"), ["va"]);
-    assert!(Instruction::split_line("").is_empty());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_words(line: &str) -> Vec<&str> {
+        Instruction::split_line(line).into_iter().map(|(_, word)| word).collect()
+    }
+
+    #[test]
+    fn test_instruction_split_line() {
+        assert_eq!(split_words("a b c"), ["a", "b", "c"]);
+        assert_eq!(split_words("a \"b c d\" ef g"), ["a", "\"b c d\"", "ef", "g"]);
+        assert_eq!(split_words("va"), ["va"]);
+        assert_eq!(split_words("  a   b\r"), ["a", "b"]);
+        assert_eq!(Instruction::split_line("  a   b"), [(2, "a"), (6, "b")]);
+        assert!(split_words("").is_empty());
+    }
+
+    #[test]
+    fn test_instruction_split_line_comments() {
+        assert!(split_words("# comment").is_empty());
+        assert!(split_words("   #").is_empty());
+        assert_eq!(split_words("set x 1 # comment"), ["set", "x", "1"]);
+        assert_eq!(split_words("set x 1#comment"), ["set", "x", "1"]);
+        assert_eq!(split_words("print \"a # b\" # c"), ["print", "\"a # b\""]);
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("12"), Some(12.));
+        assert_eq!(parse_number("-1.5"), Some(-1.5));
+        assert_eq!(parse_number(".5"), Some(0.5));
+        assert_eq!(parse_number("-.25"), Some(-0.25));
+        assert_eq!(parse_number("2."), Some(2.));
+        assert_eq!(parse_number("1e3"), Some(1000.));
+        assert_eq!(parse_number("2.5E-1"), Some(0.25));
+        assert_eq!(parse_number("0x1F"), Some(31.));
+        assert_eq!(parse_number("0b101"), Some(5.));
+        assert_eq!(parse_number("%ff0000"), Some(f64::from_bits(0xff0000ff)));
+        assert_eq!(parse_number("%00ff0080"), Some(f64::from_bits(0x00ff0080)));
+        assert_eq!(parse_number("%[red]"), parse_number("%ff0000ff"));
+        for invalid in ["", ".", "e5", "1e", "1.2.3", "inf", "NaN", "0x", "0b102", "-0xff", "+0b1", "0x-1",
+                        "0xfffffffffffffffff", "%[nothing]", "%fff", "x1"] {
+            assert_eq!(parse_number(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_instruction_parse_errors() {
+        let mut vars = Variables::from([]);
+        let labels = HashMap::new();
+        let parse = |line: &str, vars: &mut Variables|
+            Instruction::parse(line, 3, &labels, vars).map(|_| ()).unwrap_err();
+        assert_eq!(parse("  foo 1", &mut vars), ParseError {
+            line: 3, column: 3, kind: ParseErrorKind::UnknownInstruction("foo".to_string()),
+        });
+        assert_eq!(parse("set x", &mut vars), ParseError {
+            line: 3, column: 6, kind: ParseErrorKind::MissingArgument("set".to_string(), 2),
+        });
+        assert_eq!(parse("op plus x 1 2", &mut vars), ParseError {
+            line: 3, column: 4, kind: ParseErrorKind::InvalidOperator("plus".to_string()),
+        });
+        assert_eq!(parse("print  \"abc", &mut vars), ParseError {
+            line: 3, column: 8, kind: ParseErrorKind::UnterminatedString,
+        });
+        assert_eq!(parse("print \"", &mut vars), ParseError {
+            line: 3, column: 7, kind: ParseErrorKind::UnterminatedString,
+        });
+        assert_eq!(parse("set x 0xffffffffffffffffff", &mut vars), ParseError {
+            line: 3, column: 7, kind: ParseErrorKind::InvalidNumber("0xffffffffffffffffff".to_string()),
+        });
+        assert_eq!(parse("set x -0xff", &mut vars), ParseError {
+            line: 3, column: 7, kind: ParseErrorKind::InvalidNumber("-0xff".to_string()),
+        });
+        assert!(Instruction::parse("   ", 1, &labels, &mut vars).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_instruction_parse_span() {
+        let mut vars = Variables::from([]);
+        let (_, span) = Instruction::parse("  set x \"ä b\" # c", 4, &HashMap::new(), &mut vars)
+            .unwrap().unwrap();
+        assert_eq!(span, SourceSpan { line: 4, start_column: 3, end_column: 14 });
+    }
+
+    #[test]
+    fn test_instruction_parse_labels() {
+        let mut vars = Variables::from([]);
+        let code = Instruction::parse_program(
+            "start:\n# comment\nset x 1\nloop: # loop\njump loop always\njump end always\nend:", &mut vars,
+        ).unwrap();
+        assert_eq!(code.len(), 3);
+        assert!(matches!(&code[1].0, Instruction::Jump(ValueArg::Value(Value::Num(1.)), _, _, _)));
+        assert!(matches!(&code[2].0, Instruction::Jump(ValueArg::Value(Value::Num(0.)), _, _, _)));
+        assert_eq!(code[1].1, SourceSpan { line: 5, start_column: 1, end_column: 17 });
+
+        let err = Instruction::parse_program("a:\nend\n a:", &mut vars).unwrap_err();
+        assert_eq!((err.line, err.column, err.kind), (3, 2, ParseErrorKind::DuplicateLabel("a".to_string())));
+        let err = Instruction::parse_program("jump nowhere always", &mut vars).unwrap_err();
+        assert_eq!((err.line, err.column, err.kind), (1, 6, ParseErrorKind::UndefinedLabel("nowhere".to_string())));
+    }
 }
@@ -1,28 +1,91 @@
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::rc::Rc;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
-use crate::building::{Building, MemoryBuilding, MessageBuilding};
-use crate::vm::{VmError, VmFinishReason, VM};
+use crate::building::{Building, DisplayBuilding, MemoryBuilding, MessageBuilding};
+use crate::profile::Profile;
+use crate::rng::Rng;
+use crate::value::{CoercionMode, LazyUtf16String, Value};
+use crate::vm::{PosVmError, VmError, VmFinishReason, VmResult, VM};
+use crate::world::World;
+
+/// Creates buildings implemented outside the emulator.
+pub trait BuildingFactory : Debug + Send + Sync {
+    fn build(&self, name: String) -> Arc<dyn Building>;
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub enum Device {
     Message,
     Memory(usize),
+    /// A display with the given size in pixels,
+    /// either `DisplayBuilding::LOGIC_DISPLAY_SIZE` or `DisplayBuilding::LARGE_LOGIC_DISPLAY_SIZE`.
+    Display(usize),
+    /// Only available through the library, not in JSON input.
+    #[serde(skip)]
+    Custom(Arc<dyn BuildingFactory>),
+}
+
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum ImageFormat {
+    #[default]
+    Rgba,
+    Png,
+}
+
+/// Initial contents of a device, in the same shape as `DeviceState`.
+#[derive(Debug, Clone, Deserialize)]
+pub enum DeviceContents {
+    Message(String),
+    /// Values of the first memory cells, the rest stay zeroed.
+    Memory(Vec<f64>),
 }
 
 impl Device {
-    pub fn construct(self, name: String) -> (Rc<dyn Building>, Box<dyn FnOnce() -> DeviceState>) {
-        match self {
-            Device::Message => {
-                let dev = Rc::new(MessageBuilding::new(name));
+    pub fn construct(self, name: String, image_format: ImageFormat, contents: Option<DeviceContents>)
+        -> VmResult<(Arc<dyn Building>, DeviceStateGetter)> {
+        if !matches!((&self, &contents), (_, None)
+            | (Device::Message, Some(DeviceContents::Message(_)))
+            | (Device::Memory(_), Some(DeviceContents::Memory(_)))) {
+            return Err(VmError::InvalidDeviceContents(name));
+        }
+        Ok(match (self, contents) {
+            (Device::Message, contents) => {
+                let dev = Arc::new(MessageBuilding::new(name));
+                if let Some(DeviceContents::Message(text)) = contents {
+                    dev.set_text(text);
+                }
                 (dev.clone(), Box::new(move || DeviceState::Message(dev.get_text())))
             },
-            Device::Memory(capacity) => {
-                let dev = Rc::new(MemoryBuilding::new(name, capacity));
+            (Device::Memory(capacity), contents) => {
+                let dev = Arc::new(MemoryBuilding::new(name, capacity));
+                if let Some(DeviceContents::Memory(data)) = contents {
+                    dev.set_data(&data)?;
+                }
                 (dev.clone(), Box::new(move || DeviceState::Memory(dev.get_data())))
-            }
-        }
+            },
+            (Device::Display(size), _) => {
+                if ![DisplayBuilding::LOGIC_DISPLAY_SIZE, DisplayBuilding::LARGE_LOGIC_DISPLAY_SIZE].contains(&size) {
+                    return Err(VmError::InvalidDisplaySize(name, size));
+                }
+                let dev = Arc::new(DisplayBuilding::new(name, size));
+                (dev.clone(), Box::new(move || {
+                    let framebuffer = dev.get_framebuffer();
+                    DeviceState::Display {
+                        size,
+                        format: image_format,
+                        data: match image_format {
+                            ImageFormat::Rgba => framebuffer.to_rgba(),
+                            ImageFormat::Png => framebuffer.to_png(),
+                        },
+                    }
+                }))
+            },
+            (Device::Custom(factory), _) => (factory.build(name), Box::new(|| DeviceState::Custom)),
+        })
     }
 }
 
@@ -31,21 +94,158 @@ pub struct Options {
     pub code: String,
     pub code_len_limit: Option<usize>,
     pub instruction_limit: Option<usize>,
+    pub tick_limit: Option<u64>,
+    pub ipt: Option<usize>,
+    pub end_on_wrap: bool,
+    pub devices: Vec<(String, Device)>,
+    pub image_format: Option<ImageFormat>,
+    pub coercion: Option<CoercionMode>,
+    /// Seed of the random number generator, a random one is used if not specified.
+    pub seed: Option<u64>,
+    /// File to write a JSONL trace of every executed instruction to.
+    pub trace_path: Option<String>,
+    /// Count executions of every instruction.
+    #[serde(default)]
+    pub profile: bool,
+    /// Include the final values of the variables used by the program in the output.
+    #[serde(default)]
+    pub include_variables: bool,
+    /// Initial values of variables used by the program, others are ignored.
+    #[serde(default)]
+    pub variables: HashMap<String, VariableValue>,
+    /// Initial contents of devices, by name.
+    #[serde(default)]
+    pub device_contents: HashMap<String, DeviceContents>,
+}
+
+/// A processor in a multi-processor run.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcessorOptions {
+    pub code: String,
+    pub ipt: Option<usize>,
+    /// Names of the linked devices, all devices are linked if not specified.
+    pub links: Option<Vec<String>>,
+    /// Initial values of variables used by the program, others are ignored.
+    #[serde(default)]
+    pub variables: HashMap<String, VariableValue>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorldOptions {
+    pub processors: Vec<ProcessorOptions>,
+    pub code_len_limit: Option<usize>,
+    /// Instruction limit of each processor.
+    pub instruction_limit: Option<usize>,
+    pub tick_limit: Option<u64>,
     pub end_on_wrap: bool,
     pub devices: Vec<(String, Device)>,
+    pub image_format: Option<ImageFormat>,
+    pub coercion: Option<CoercionMode>,
+    /// Seed of the random number generator of the first processor, the following ones use
+    /// consecutive seeds. A random one is used if not specified.
+    pub seed: Option<u64>,
+    /// Initial contents of devices, by name.
+    #[serde(default)]
+    pub device_contents: HashMap<String, DeviceContents>,
+}
+
+/// Input of `run_from_json`, either a single processor or several ones.
+#[derive(Debug)]
+pub enum Input {
+    World(WorldOptions),
+    Single(Options),
+}
+
+impl Input {
+    /// Parses the options of several processors if the input has a `processors` field,
+    /// and of a single one otherwise.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        if value.get("processors").is_some() {
+            serde_json::from_str(json).map(Input::World)
+        } else {
+            serde_json::from_str(json).map(Input::Single)
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub enum DeviceState {
     Message(String),
     Memory(Box<[f64]>),
+    /// Display contents, rows from the top, in the requested format.
+    Display {
+        size: usize,
+        format: ImageFormat,
+        data: Vec<u8>,
+    },
+    /// A building implemented outside the emulator, its state is unknown.
+    Custom,
+}
+
+/// Value of a variable, tagged with its type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum VariableValue {
+    Null,
+    Num(f64),
+    Str(String),
+    /// Name of the building.
+    Building(String),
+    /// Name of the property, without the `@` prefix.
+    Property(String),
+}
+
+impl VariableValue {
+    /// Converts to a value of `vm`, buildings must be linked to it.
+    pub fn to_value(&self, vm: &VM) -> VmResult<Value> {
+        Ok(match self {
+            VariableValue::Null => Value::Null,
+            VariableValue::Num(num) => Value::Num(*num),
+            VariableValue::Str(string) =>
+                Value::Str(Arc::new(LazyUtf16String::new(Arc::new(string.clone())))),
+            VariableValue::Building(name) => Value::Building(vm.get_val(name)
+                .and_then(|value| value.as_building())
+                .map_err(|_| VmError::DeviceNotFound(name.clone()))?),
+            VariableValue::Property(name) => vm.get_val(&format!("@{}", name))
+                .and_then(|value| value.as_property().map(Value::Property))?,
+        })
+    }
+}
+
+impl From<&Value> for VariableValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => VariableValue::Null,
+            Value::Num(num) => VariableValue::Num(*num),
+            Value::Str(string) => VariableValue::Str(string.to_string()),
+            Value::Building(building) => VariableValue::Building(building.name().to_string()),
+            Value::Property(property) => VariableValue::Property(property.name().to_string()),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub enum ErrorPos {
-    Instruction(usize),
+    Instruction {
+        index: usize,
+        line: usize,
+        start_column: usize,
+        end_column: usize,
+    },
     None,
-    PcFetch
+    PcFetch,
+    Parse {
+        line: usize,
+        column: usize,
+    },
+    /// The input JSON is malformed or does not match the options, the location is 1-based.
+    Input {
+        line: usize,
+        column: usize,
+    },
+    /// The program or the devices could not be set up.
+    Load,
 }
 
 #[derive(Debug, Serialize)]
@@ -54,51 +254,523 @@ pub enum Output {
         finish_reason: VmFinishReason,
         devices: HashMap<String, DeviceState>,
         print_buffer: String,
+        ticks: u64,
+        seed: u64,
+        profile: Option<Profile>,
+        variables: Option<HashMap<String, VariableValue>>,
     },
     Failure {
         pos: ErrorPos,
         msg: String,
+        /// Seed the run used, to replay it. Unknown if the input could not be read.
+        seed: Option<u64>,
+        /// Counts up to and including the failing instruction, if profiling and the program was loaded.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        profile: Option<Profile>,
     },
 }
 
-pub fn run_from_options(options: Options) -> Output {
-    let mut devices = vec![];
-    let mut device_state_getters = vec![];
-    for (name, device) in options.devices {
-        let (device, getter) = device.construct(name.clone());
-        devices.push(device);
-        device_state_getters.push((name, getter));
+#[derive(Debug, Serialize)]
+pub enum ProcessorOutput {
+    Success {
+        finish_reason: VmFinishReason,
+        print_buffer: String,
+    },
+    Failure {
+        pos: ErrorPos,
+        msg: String,
+        /// Seed of the processor, to replay the run.
+        seed: u64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorldOutput {
+    pub processors: Vec<ProcessorOutput>,
+    pub devices: HashMap<String, DeviceState>,
+    pub ticks: u64,
+    pub seed: u64,
+}
+
+pub type DeviceStateGetter = Box<dyn Fn() -> DeviceState + Send + Sync>;
+type DeviceStateGetters = Vec<(String, DeviceStateGetter)>;
+
+fn construct_devices(devices: Vec<(String, Device)>, mut contents: HashMap<String, DeviceContents>,
+                     image_format: Option<ImageFormat>) -> VmResult<(Vec<Arc<dyn Building>>, DeviceStateGetters)> {
+    let devices = devices.into_iter()
+        .map(|(name, device)| {
+            let contents = contents.remove(&name);
+            let (device, getter) = device.construct(name.clone(), image_format.unwrap_or_default(), contents)?;
+            Ok((device, (name, getter)))
+        })
+        .collect::<VmResult<Vec<_>>>()?;
+    if let Some(name) = contents.into_keys().next() {
+        return Err(VmError::DeviceNotFound(name));
+    }
+    Ok(devices.into_iter().unzip())
+}
+
+/// Sets the initial values of variables. Variables the program never uses are ignored,
+/// since they cannot affect the run, but constants still cannot be set.
+fn set_variables(vm: &VM, variables: &HashMap<String, VariableValue>) -> VmResult<()> {
+    for (name, value) in variables {
+        if vm.get_val(name).is_ok() {
+            vm.set_var(name, value.to_value(vm)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn load_error_pos(err: &VmError) -> ErrorPos {
+    match err {
+        VmError::Parse(err) => ErrorPos::Parse {
+            line: err.line,
+            column: err.column,
+        },
+        _ => ErrorPos::Load,
+    }
+}
+
+fn run_error_pos(err: &PosVmError) -> ErrorPos {
+    match &err.1 {
+        Some((index, span)) => ErrorPos::Instruction {
+            index: *index,
+            line: span.line,
+            start_column: span.start_column,
+            end_column: span.end_column,
+        },
+        None => match &err.0 {
+            VmError::PcResError(_) => ErrorPos::PcFetch,
+            _ => ErrorPos::None,
+        },
+    }
+}
+
+/// A failed run, before the error is rendered into `Output::Failure`.
+#[derive(Debug)]
+pub struct RunFailure {
+    pub pos: ErrorPos,
+    pub error: PosVmError,
+    pub seed: u64,
+    pub profile: Option<Profile>,
+}
+
+impl RunFailure {
+    fn load(error: VmError, seed: u64) -> Box<Self> {
+        Box::new(RunFailure {
+            pos: ErrorPos::Load,
+            error: error.to_pos(),
+            seed,
+            profile: None,
+        })
+    }
+}
+
+impl From<RunFailure> for Output {
+    fn from(value: RunFailure) -> Self {
+        Output::Failure {
+            pos: value.pos,
+            msg: value.error.to_string(),
+            seed: Some(value.seed),
+            profile: value.profile,
+        }
     }
+}
+
+impl From<RunFailure> for ProcessorOutput {
+    fn from(value: RunFailure) -> Self {
+        ProcessorOutput::Failure {
+            pos: value.pos,
+            msg: value.error.to_string(),
+            seed: value.seed,
+        }
+    }
+}
+
+/// A successful run, with the same contents as `Output::Success`.
+#[derive(Debug)]
+pub struct RunSuccess {
+    pub finish_reason: VmFinishReason,
+    pub devices: HashMap<String, DeviceState>,
+    pub print_buffer: String,
+    pub ticks: u64,
+    pub seed: u64,
+    pub profile: Option<Profile>,
+    pub variables: Option<HashMap<String, VariableValue>>,
+}
+
+impl From<RunSuccess> for Output {
+    fn from(value: RunSuccess) -> Self {
+        Output::Success {
+            finish_reason: value.finish_reason,
+            devices: value.devices,
+            print_buffer: value.print_buffer,
+            ticks: value.ticks,
+            seed: value.seed,
+            profile: value.profile,
+            variables: value.variables,
+        }
+    }
+}
+
+pub fn run_from_options(options: Options) -> Output {
+    try_run_from_options(options).map_or_else(|failure| Output::from(*failure), Output::from)
+}
 
-    let vm = VM::new(
+/// Like `run_from_options`, but failures keep the error.
+pub fn try_run_from_options(options: Options) -> Result<RunSuccess, Box<RunFailure>> {
+    let seed = options.seed.unwrap_or_else(Rng::random_seed);
+    let (devices, device_state_getters) =
+        construct_devices(options.devices, options.device_contents, options.image_format)
+            .map_err(|err| RunFailure::load(err, seed))?;
+
+    let mut vm = VM::new(
         &options.code,
         options.code_len_limit.unwrap_or(VM::DEFAULT_CODE_LEN_LIMIT),
         devices,
-    ).unwrap();
-    match vm.run(options.instruction_limit, options.end_on_wrap) {
-        Ok(finish_reason) => Output::Success {
+    ).map_err(|err| Box::new(RunFailure {
+        pos: load_error_pos(&err),
+        error: err.to_pos(),
+        seed,
+        profile: None,
+    }))?;
+    vm.set_coercion_mode(options.coercion.unwrap_or_default());
+    vm.set_ipt(options.ipt.unwrap_or(VM::DEFAULT_IPT));
+    vm.set_seed(seed);
+    vm.set_profiling(options.profile);
+    set_variables(&vm, &options.variables).map_err(|err| RunFailure::load(err, seed))?;
+    if let Some(path) = options.trace_path {
+        let file = File::create(path).map_err(|err| RunFailure::load(VmError::Trace(err), seed))?;
+        vm.set_trace(Some(Box::new(BufWriter::new(file))));
+    }
+    match vm.run(options.instruction_limit, options.tick_limit, options.end_on_wrap) {
+        Ok(finish_reason) => Ok(RunSuccess {
             finish_reason,
             devices: device_state_getters
                 .into_iter()
                 .map(|(name, getter)| (name, getter()))
                 .collect(),
+            ticks: vm.ticks(),
+            seed: vm.seed(),
+            profile: vm.profile(),
+            variables: options.include_variables.then(|| vm.user_variables()
+                .into_iter()
+                .map(|(name, value)| (name, VariableValue::from(&value)))
+                .collect()),
             print_buffer: vm.into_print_buffer().take(),
-        },
-        Err(err) => Output::Failure {
-            pos: match &err.1 {
-                Some(pos) => ErrorPos::Instruction(*pos),
-                None => match &err.0 {
-                    VmError::PcResError(_) => ErrorPos::PcFetch,
-                    _ => ErrorPos::None,
-                },
+        }),
+        Err(err) => Err(Box::new(RunFailure {
+            pos: run_error_pos(&err),
+            error: err,
+            seed,
+            profile: vm.profile(),
+        })),
+    }
+}
+
+/// Runs independent jobs on `threads` threads, returning the results in the order of `jobs`.
+/// Every VM is created and dropped on the thread running it.
+pub fn run_batch(jobs: Vec<Options>, threads: usize) -> Vec<Result<RunSuccess, Box<RunFailure>>> {
+    let threads = threads.clamp(1, jobs.len().max(1));
+    let jobs = jobs.into_iter().map(|job| Mutex::new(Some(job))).collect::<Vec<_>>();
+    let next = AtomicUsize::new(0);
+    let mut outputs = std::thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| scope.spawn(|| {
+                let mut outputs = vec![];
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(i) else {
+                        break outputs;
+                    };
+                    let options = job.lock().unwrap().take().unwrap();
+                    outputs.push((i, try_run_from_options(options)));
+                }
+            }))
+            .collect::<Vec<_>>();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+    });
+    outputs.sort_by_key(|(i, _)| *i);
+    outputs.into_iter().map(|(_, output)| output).collect()
+}
+
+/// A multi-processor run whose failed processors keep their errors.
+#[derive(Debug)]
+pub struct WorldRun {
+    pub processors: Vec<Result<ProcessorOutput, Box<RunFailure>>>,
+    pub devices: HashMap<String, DeviceState>,
+    pub ticks: u64,
+    pub seed: u64,
+}
+
+impl From<WorldRun> for WorldOutput {
+    fn from(value: WorldRun) -> Self {
+        WorldOutput {
+            processors: value.processors.into_iter()
+                .map(|output| output.unwrap_or_else(|failure| ProcessorOutput::from(*failure)))
+                .collect(),
+            devices: value.devices,
+            ticks: value.ticks,
+            seed: value.seed,
+        }
+    }
+}
+
+/// Runs several processors sharing the same devices.
+/// Processors that fail to load are reported as failures and the rest still run.
+pub fn run_world_from_options(options: WorldOptions) -> WorldOutput {
+    let count = options.processors.len();
+    try_run_world_from_options(options).map_or_else(|failure| WorldOutput {
+        processors: (0..count)
+            .map(|i| ProcessorOutput::Failure {
+                pos: ErrorPos::Load,
+                msg: failure.error.to_string(),
+                seed: failure.seed.wrapping_add(i as u64),
+            })
+            .collect(),
+        devices: HashMap::new(),
+        ticks: 0,
+        seed: failure.seed,
+    }, WorldOutput::from)
+}
+
+/// Like `run_world_from_options`, but failures keep the error.
+/// Fails as a whole, with the seed of the world, if the devices cannot be set up.
+pub fn try_run_world_from_options(options: WorldOptions) -> Result<WorldRun, Box<RunFailure>> {
+    let names = options.devices.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+    let seed = options.seed.unwrap_or_else(Rng::random_seed);
+    let (devices, device_state_getters) =
+        construct_devices(options.devices, options.device_contents, options.image_format)
+            .map_err(|err| RunFailure::load(err, seed))?;
+
+    let mut world = World::new();
+    let mut outputs = vec![];
+    for (i, processor) in options.processors.into_iter().enumerate() {
+        let links = match processor.links {
+            Some(links) => links.into_iter()
+                .map(|link| names.iter().position(|name| *name == link)
+                    .map(|i| devices[i].clone())
+                    .ok_or(VmError::DeviceNotFound(link)))
+                .collect::<Result<Vec<_>, _>>(),
+            None => Ok(devices.clone()),
+        };
+        let vm = links
+            .and_then(|links| VM::new(
+                &processor.code,
+                options.code_len_limit.unwrap_or(VM::DEFAULT_CODE_LEN_LIMIT),
+                links,
+            ))
+            .and_then(|vm| set_variables(&vm, &processor.variables).map(|()| vm));
+        let processor_seed = seed.wrapping_add(i as u64);
+        outputs.push(match vm {
+            Ok(mut vm) => {
+                vm.set_coercion_mode(options.coercion.unwrap_or_default());
+                vm.set_ipt(processor.ipt.unwrap_or(VM::DEFAULT_IPT));
+                vm.set_seed(processor_seed);
+                world.add_processor(vm);
+                Ok(())
             },
-            msg: err.to_string(),
-        },
+            Err(err) => Err(Box::new(RunFailure {
+                pos: load_error_pos(&err),
+                error: err.to_pos(),
+                seed: processor_seed,
+                profile: None,
+            })),
+        });
     }
+
+    world.run(options.instruction_limit, options.tick_limit, options.end_on_wrap);
+    let ticks = world.ticks();
+    let mut results = world.into_results().into_iter();
+    Ok(WorldRun {
+        processors: outputs.into_iter()
+            .map(|output| output.and_then(|()| {
+                let (vm, result) = results.next().unwrap();
+                match result.unwrap_or(Ok(VmFinishReason::TickLimit)) {
+                    Ok(finish_reason) => Ok(ProcessorOutput::Success {
+                        finish_reason,
+                        print_buffer: vm.into_print_buffer().take(),
+                    }),
+                    Err(err) => Err(Box::new(RunFailure {
+                        pos: run_error_pos(&err),
+                        error: err,
+                        seed: vm.seed(),
+                        profile: None,
+                    })),
+                }
+            }))
+            .collect(),
+        devices: device_state_getters
+            .into_iter()
+            .map(|(name, getter)| (name, getter()))
+            .collect(),
+        ticks,
+        seed,
+    })
+}
+
+/// Reads options from `input` and writes the output of the run to `output`.
+/// Malformed input is reported as an `Output::Failure`.
+/// Returns whether every processor ran without failing and the output was written.
+pub fn run_from_json(mut input: impl Read, output: impl Write) -> bool {
+    let mut json = String::new();
+    let input = input.read_to_string(&mut json)
+        .map_err(serde_json::Error::io)
+        .and_then(|_| Input::from_json(&json));
+    let (written, success) = match input {
+        Ok(Input::Single(options)) => {
+            let result = run_from_options(options);
+            (serde_json::to_writer(output, &result), matches!(result, Output::Success { .. }))
+        },
+        Ok(Input::World(options)) => {
+            let result = run_world_from_options(options);
+            let success = result.processors.iter().all(|p| matches!(p, ProcessorOutput::Success { .. }));
+            (serde_json::to_writer(output, &result), success)
+        },
+        Err(err) => (serde_json::to_writer(output, &Output::Failure {
+            pos: ErrorPos::Input {
+                line: err.line(),
+                column: err.column(),
+            },
+            msg: format!("Invalid input: {}", err),
+            seed: None,
+            profile: None,
+        }), false),
+    };
+    written.is_ok() && success
 }
 
-pub fn run_from_json(input: impl Read, output: impl Write) {
-    let options: Options = serde_json::from_reader(input).unwrap();
-    let result = run_from_options(options);
-    serde_json::to_writer(output, &result).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_json(input: &str) -> (bool, String) {
+        let mut output = vec![];
+        let success = run_from_json(input.as_bytes(), &mut output);
+        (success, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_interface_input_errors() {
+        let (success, output) = run_json(r#"{"code": "print 1", "end_on_wrap": true, "devices": []"#);
+        assert!(!success);
+        assert!(output.starts_with(r#"{"Failure":{"pos":{"Input":{"line":1,"column":54}},"msg":"Invalid input: EOF"#));
+
+        let (success, output) = run_json(r#"{"code": "print 1", "end_on_wrap": true}"#);
+        assert!(!success);
+        assert!(output.contains("missing field `devices`"));
+
+        let (success, _) = run_json(r#"{"code": "print 1", "end_on_wrap": true, "devices": []}"#);
+        assert!(success);
+    }
+
+    #[test]
+    fn test_interface_load_errors() {
+        let (success, output) = run_json(r#"{"processors": [{"code": "stop"}, {"code": "x"}],
+                                             "end_on_wrap": true, "devices": []}"#);
+        assert!(!success);
+        assert!(output.contains(r#""pos":{"Parse":{"line":1,"column":1}}"#));
+
+        let (success, output) = run_json(r#"{"code": "print 1", "end_on_wrap": true,
+                                             "devices": [["cell1", {"Memory": 2}], ["cell1", "Message"]]}"#);
+        assert!(!success);
+        assert!(output.starts_with(r#"{"Failure":{"pos":"Load","msg":"Error: Duplicate device name: 'cell1'""#));
+
+        let (success, output) = run_json(r#"{"processors": [{"code": "stop", "links": []}, {"code": "stop"}],
+                                             "end_on_wrap": true, "devices": [["@unit", "Message"]]}"#);
+        assert!(!success);
+        assert!(output.starts_with(r#"{"processors":[{"Success""#));
+        assert!(output.contains(r#"{"Failure":{"pos":"Load","msg":"Error: Duplicate device name: '@unit'""#));
+    }
+
+    #[test]
+    fn test_interface_failure_seed() {
+        let (success, output) = run_json(r#"{"code": "", "end_on_wrap": true, "devices": [], "seed": 5}"#);
+        assert!(!success);
+        assert_eq!(output, r#"{"Failure":{"pos":"Load","msg":"Error: Program is empty","seed":5}}"#);
+
+        let (success, output) = run_json(r#"{"code": "op idiv x 1 0", "end_on_wrap": true, "devices": [], "seed": 7}"#);
+        assert!(!success);
+        assert!(output.ends_with(r#""seed":7}}"#));
+    }
+
+    #[test]
+    fn test_interface_failure_profile() {
+        let (success, output) = run_json(r#"{"code": "set x 0\nop idiv x 1 x", "end_on_wrap": true, "devices": [],
+                                             "profile": true}"#);
+        assert!(!success);
+        let output = serde_json::from_str::<serde_json::Value>(&output).unwrap();
+        let profile = &output["Failure"]["profile"];
+        assert_eq!(profile["total"], 2);
+        assert_eq!(profile["instructions"][1]["executions"], 1);
+    }
+
+    #[test]
+    fn test_interface_initial_values() {
+        let (success, output) = run_json(r#"{"code": "print 1", "end_on_wrap": true, "devices": [["cell1", {"Memory": 2}]],
+                                             "device_contents": {"cell1": {"Memory": [1, 2, 3]}}}"#);
+        assert!(!success);
+        assert!(output.contains(r#""msg":"Error: Invalid initial contents for device 'cell1'""#));
+
+        let (success, output) = run_json(r#"{"code": "print 1", "end_on_wrap": true, "devices": [],
+                                             "variables": {"unused": {"type": "Num", "value": 1}}}"#);
+        assert!(success);
+        assert!(output.contains(r#""print_buffer":"1""#));
+
+        let (success, output) = run_json(r#"{"code": "print 1", "end_on_wrap": true, "devices": [],
+                                             "variables": {"@pi": {"type": "Num", "value": 1}}}"#);
+        assert!(!success);
+        assert!(output.contains(r#""pos":"Load""#));
+    }
+
+    #[test]
+    fn test_interface_display_size() {
+        let (success, _) = run_json(r#"{"code": "drawflush display1", "end_on_wrap": true,
+                                        "devices": [["display1", {"Display": 176}]]}"#);
+        assert!(success);
+
+        let (success, output) = run_json(r#"{"code": "drawflush display1", "end_on_wrap": true,
+                                             "devices": [["display1", {"Display": 100000000}]]}"#);
+        assert!(!success);
+        assert!(output.starts_with(
+            r#"{"Failure":{"pos":"Load","msg":"Error: Invalid size 100000000 for display 'display1', must be 80 or 176""#));
+    }
+
+    #[test]
+    fn test_interface_try_run_world() {
+        let options = serde_json::from_str::<WorldOptions>(r#"{"processors": [{"code": "stop"}, {"code": "op idiv x 1 0"}],
+                                                              "end_on_wrap": true, "devices": [], "seed": 3}"#).unwrap();
+        let run = try_run_world_from_options(options).unwrap();
+        assert!(matches!(run.processors[0], Ok(ProcessorOutput::Success { .. })));
+        let Err(failure) = &run.processors[1] else { panic!() };
+        assert!(matches!(failure.error, PosVmError(VmError::DivisionByZero, Some((0, _)))));
+        assert_eq!(failure.seed, 4);
+
+        let options = serde_json::from_str::<WorldOptions>(r#"{"processors": [{"code": "stop"}], "end_on_wrap": true,
+                                                              "devices": [], "device_contents": {"cell1": {"Memory": []}}}"#)
+            .unwrap();
+        let failure = try_run_world_from_options(options).unwrap_err();
+        assert!(matches!(&failure.error.0, VmError::DeviceNotFound(name) if name == "cell1"));
+    }
+
+    #[test]
+    fn test_interface_run_batch() {
+        let jobs = (0..20)
+            .map(|i| serde_json::from_str::<Options>(
+                &format!(r#"{{"code": "print {i}\nop idiv x 1 {}", "end_on_wrap": true, "devices": []}}"#, i % 3))
+                .unwrap())
+            .collect::<Vec<_>>();
+        let outputs = run_batch(jobs, 4);
+        assert_eq!(outputs.len(), 20);
+        for (i, output) in outputs.into_iter().enumerate() {
+            match output {
+                Ok(success) => assert_eq!(success.print_buffer, i.to_string()),
+                Err(failure) => {
+                    assert_eq!(i % 3, 0);
+                    assert!(matches!(failure.error.0, VmError::DivisionByZero));
+                },
+            }
+        }
+        assert!(run_batch(vec![], 4).is_empty());
+    }
 }
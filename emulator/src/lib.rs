@@ -1,11 +1,17 @@
-#![feature(unsafe_cell_access, random)]
-
 pub mod vm;
 pub mod value;
 pub mod building;
 pub mod variable;
 pub mod instruction;
 pub mod interface;
+pub mod color;
+pub mod draw;
+pub mod world;
+pub mod rng;
+pub mod debugger;
+pub mod trace;
+pub mod profile;
+pub mod coverage;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
@@ -0,0 +1,83 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use serde::Serialize;
+use crate::instruction::{Instruction, SourceSpan};
+
+/// Execution counts of a single instruction.
+#[derive(Debug, Clone, Serialize)]
+pub struct InstructionProfile {
+    pub index: usize,
+    pub line: usize,
+    pub opcode: &'static str,
+    pub executions: u64,
+    /// Number of times the jump was taken, only present for conditional jumps.
+    pub jumps_taken: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    /// Total number of executed instructions.
+    pub total: u64,
+    /// Counts of every instruction, in program order.
+    pub instructions: Vec<InstructionProfile>,
+}
+
+impl Profile {
+    /// Formats the executed instructions as a table, most executed first.
+    /// Every instruction costs the same share of the instructions per tick.
+    pub fn report(&self) -> String {
+        let mut ranked = self.instructions.iter().filter(|p| p.executions > 0).collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.executions.cmp(&a.executions).then(a.index.cmp(&b.index)));
+        let mut report = format!("{} instructions executed\n", self.total);
+        report.push_str(" index   line  executions   share  instruction\n");
+        for p in ranked {
+            let share = p.executions as f64 / self.total as f64 * 100.;
+            write!(report, "{:>6} {:>6} {:>11} {:>6.2}%  {}", p.index, p.line, p.executions, share, p.opcode)
+                .unwrap();
+            if let Some(taken) = p.jumps_taken {
+                write!(report, " (taken {}/{})", taken, p.executions).unwrap();
+            }
+            report.push('\n');
+        }
+        report
+    }
+}
+
+/// Counts executed instructions and taken jumps.
+#[derive(Debug)]
+pub struct Profiler {
+    executions: Vec<Cell<u64>>,
+    jumps_taken: Vec<Cell<u64>>,
+}
+
+impl Profiler {
+    pub fn new(len: usize) -> Self {
+        Profiler {
+            executions: (0..len).map(|_| Cell::new(0)).collect(),
+            jumps_taken: (0..len).map(|_| Cell::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, index: usize, jumped: bool) {
+        self.executions[index].set(self.executions[index].get() + 1);
+        if jumped {
+            self.jumps_taken[index].set(self.jumps_taken[index].get() + 1);
+        }
+    }
+
+    pub fn profile(&self, code: &[Instruction], source_map: &[SourceSpan]) -> Profile {
+        let instructions = code.iter().zip(source_map).enumerate()
+            .map(|(index, (ins, span))| InstructionProfile {
+                index,
+                line: span.line,
+                opcode: ins.opcode(),
+                executions: self.executions[index].get(),
+                jumps_taken: ins.is_conditional_jump().then(|| self.jumps_taken[index].get()),
+            })
+            .collect::<Vec<_>>();
+        Profile {
+            total: instructions.iter().map(|p| p.executions).sum(),
+            instructions,
+        }
+    }
+}
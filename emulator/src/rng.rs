@@ -0,0 +1,62 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Deterministic SplitMix64 pseudo-random number generator.
+#[derive(Debug)]
+pub struct Rng {
+    seed: u64,
+    state: Cell<u64>,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            seed,
+            state: Cell::new(seed),
+        }
+    }
+
+    /// Returns a seed that differs between runs.
+    pub fn random_seed() -> u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(time) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            hasher.write_u128(time.as_nanos());
+        }
+        hasher.finish()
+    }
+
+    /// The seed this generator was created with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9e3779b97f4a7c15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in the range `[0, 1)`.
+    pub fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_deterministic() {
+        let a = Rng::new(42);
+        let b = Rng::new(42);
+        let values = (0..100).map(|_| a.next_f64()).collect::<Vec<_>>();
+        assert_eq!(values, (0..100).map(|_| b.next_f64()).collect::<Vec<_>>());
+        assert!(values.iter().all(|v| (0. ..1.).contains(v)));
+        assert_ne!(Rng::new(43).next_u64(), Rng::new(42).next_u64());
+    }
+}
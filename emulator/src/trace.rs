@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter};
+use std::io::Write;
+use serde::Serialize;
+use crate::instruction::{Instruction, InstructionExecuteResult, SourceSpan};
+use crate::value::Value;
+use crate::variable::Variables;
+use crate::vm::{VmError, VmResult};
+
+/// Location written by an instruction, along with the written value.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceWrite {
+    Variable { name: String, value: serde_json::Value },
+    Cell { building: String, index: serde_json::Value, value: serde_json::Value },
+}
+
+/// A single executed instruction.
+#[derive(Debug, Serialize)]
+pub struct TraceRecord {
+    pub index: usize,
+    pub line: usize,
+    pub opcode: &'static str,
+    pub inputs: Vec<serde_json::Value>,
+    pub written: Option<TraceWrite>,
+    /// Message of the error raised by the instruction, which ends the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Converts a value to JSON. Numbers, strings and `null` map to their JSON counterparts,
+/// anything else, including non-finite numbers, to its printed form.
+pub fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Num(num) => serde_json::Number::from_f64(*num)
+            .map_or_else(|| value.to_string().into(), serde_json::Value::Number),
+        value => value.to_string().into(),
+    }
+}
+
+/// Streams trace records to a writer, one JSON object per line.
+pub struct Tracer {
+    writer: RefCell<Box<dyn Write + Send>>,
+}
+
+impl Debug for Tracer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer").finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Tracer {
+            writer: RefCell::new(writer),
+        }
+    }
+
+    /// Writes the record of an executed instruction, including one that failed.
+    /// `inputs` are the values of its input operands before execution.
+    pub fn record(&self, index: usize, span: SourceSpan, instruction: &Instruction, inputs: &[Value],
+                  result: &VmResult<InstructionExecuteResult>, vars: &Variables) -> VmResult<()> {
+        let written = match result {
+            Ok(InstructionExecuteResult { written: Some(write), .. }) => Some(TraceWrite::Cell {
+                building: write.building.name().to_string(),
+                index: value_to_json(&write.index),
+                value: value_to_json(&write.value),
+            }),
+            Ok(_) => instruction.output().map(|dst| TraceWrite::Variable {
+                name: dst.get(vars).name().to_string(),
+                value: value_to_json(&dst.val(vars)),
+            }),
+            Err(_) => None,
+        };
+        let record = TraceRecord {
+            index,
+            line: span.line,
+            opcode: instruction.opcode(),
+            inputs: inputs.iter().map(value_to_json).collect(),
+            written,
+            error: result.as_ref().err().map(VmError::to_string),
+        };
+        let mut writer = self.writer.borrow_mut();
+        serde_json::to_writer(&mut *writer, &record).map_err(|err| VmError::Trace(err.into()))?;
+        writer.write_all(b"\n").map_err(VmError::Trace)
+    }
+
+    pub fn flush(&self) -> VmResult<()> {
+        self.writer.borrow_mut().flush().map_err(VmError::Trace)
+    }
+}
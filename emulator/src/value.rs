@@ -1,21 +1,21 @@
-use std::cell::OnceCell;
 use std::fmt::{Display, Formatter};
 use std::ops::Deref;
-use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+use serde::{Deserialize, Serialize};
 use crate::building::Building;
 use crate::vm::{VmError, VmResult};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct LazyUtf16String {
-    string: Rc<String>,
-    utf_16: OnceCell<Vec<u16>>,
+    string: Arc<String>,
+    utf_16: OnceLock<Vec<u16>>,
 }
 
 impl LazyUtf16String {
-    pub fn new(string: Rc<String>) -> Self {
+    pub fn new(string: Arc<String>) -> Self {
         LazyUtf16String {
             string,
-            utf_16: OnceCell::new(),
+            utf_16: OnceLock::new(),
         }
     }
 
@@ -31,26 +31,26 @@ impl LazyUtf16String {
         &self.string
     }
 
-    pub fn clone_string(&self) -> Rc<String> {
+    pub fn clone_string(&self) -> Arc<String> {
         self.string.clone()
     }
 }
 
 impl From<String> for LazyUtf16String {
     fn from(value: String) -> Self {
-        LazyUtf16String::new(Rc::new(value))
+        LazyUtf16String::new(Arc::new(value))
     }
 }
 
-impl From<Rc<String>> for LazyUtf16String {
-    fn from(value: Rc<String>) -> Self {
+impl From<Arc<String>> for LazyUtf16String {
+    fn from(value: Arc<String>) -> Self {
         LazyUtf16String::new(value)
     }
 }
 
 impl From<&str> for LazyUtf16String {
     fn from(value: &str) -> Self {
-        LazyUtf16String::new(Rc::new(value.to_string()))
+        LazyUtf16String::new(Arc::new(value.to_string()))
     }
 }
 
@@ -60,7 +60,7 @@ impl From<LazyUtf16String> for String {
     }
 }
 
-impl From<LazyUtf16String> for Rc<String> {
+impl From<LazyUtf16String> for Arc<String> {
     fn from(value: LazyUtf16String) -> Self {
         value.string
     }
@@ -95,12 +95,47 @@ impl Property {
     }
 }
 
+/// Formats a number like the game's `print` does.
+/// Numbers within 0.00001 of an integer in the range of `i64` are printed as that integer,
+/// others use the format of Java's `Double.toString`.
+pub fn format_num(num: f64) -> String {
+    // Java's Math.round
+    let rounded = (num + 0.5).floor() as i64;
+    if (num - rounded as f64).abs() < 0.00001 {
+        rounded.to_string()
+    } else if num.is_nan() {
+        "NaN".to_string()
+    } else if num.is_infinite() {
+        if num > 0. { "Infinity" } else { "-Infinity" }.to_string()
+    } else if (1e-3..1e7).contains(&num.abs()) {
+        num.to_string()
+    } else {
+        let sci = format!("{:e}", num);
+        let (mantissa, exponent) = sci.split_once('e').unwrap();
+        if mantissa.contains('.') {
+            format!("{}E{}", mantissa, exponent)
+        } else {
+            format!("{}.0E{}", mantissa, exponent)
+        }
+    }
+}
+
+/// How values of other types are converted to numbers.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CoercionMode {
+    /// Using a non-number as a number is an error.
+    #[default]
+    Strict,
+    /// Follows the game, where `null` is 0 and any other object is 1.
+    Lenient,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Null,
     Num(f64),
-    Str(Rc<LazyUtf16String>),
-    Building(Rc<dyn Building>),
+    Str(Arc<LazyUtf16String>),
+    Building(Arc<dyn Building>),
     Property(Property),
 }
 
@@ -130,6 +165,26 @@ impl Value {
         }
     }
 
+    /// Converts the value to a number like the game does.
+    /// `null`, NaN and infinities are 0, any other non-number is 1.
+    pub fn to_num_lenient(&self) -> f64 {
+        match self {
+            Value::Num(num) if num.is_finite() => *num,
+            Value::Num(_) | Value::Null => 0.,
+            _ => 1.,
+        }
+    }
+
+    /// Equality as used by the game's `equal` comparison.
+    /// Two non-numbers are compared directly, otherwise both are converted to numbers.
+    pub fn lenient_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Num(_), _) | (_, Value::Num(_)) =>
+                (self.to_num_lenient() - other.to_num_lenient()).abs() < 0.000001,
+            _ => self == other,
+        }
+    }
+
     pub fn as_int(&self) -> VmResult<i64> {
         let num = self.as_num()?;
         let rounded = num.round();
@@ -160,14 +215,14 @@ impl Value {
         Ok(data[self.as_index(data.len(), device)?])
     }
 
-    pub fn as_str(&self) -> VmResult<Rc<LazyUtf16String>> {
+    pub fn as_str(&self) -> VmResult<Arc<LazyUtf16String>> {
         match self {
             Value::Str(string) => Ok(string.clone()),
             _ => Err(self._invalid_cast("str")),
         }
     }
 
-    pub fn as_building(&self) -> VmResult<Rc<dyn Building>> {
+    pub fn as_building(&self) -> VmResult<Arc<dyn Building>> {
         match self {
             Value::Building(building) => Ok(building.clone()),
             _ => Err(self._invalid_cast("Building")),
@@ -197,10 +252,51 @@ impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Null => write!(f, "null"),
-            Value::Num(num) => write!(f, "{}", num),
+            Value::Num(num) => write!(f, "{}", format_num(*num)),
             Value::Str(string) => write!(f, "{}", string),
             Value::Building(building) => write!(f, "{}", building.name()),
             Value::Property(property) => write!(f, "@{}", property.name()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_num() {
+        assert_eq!(format_num(0.), "0");
+        assert_eq!(format_num(-0.), "0");
+        assert_eq!(format_num(42.), "42");
+        assert_eq!(format_num(-3.), "-3");
+        assert_eq!(format_num(2.5), "2.5");
+        assert_eq!(format_num(-0.5), "-0.5");
+        assert_eq!(format_num(1.000001), "1");
+        assert_eq!(format_num(0.9999), "0.9999");
+        assert_eq!(format_num(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_num(0.0001), "1.0E-4");
+        assert_eq!(format_num(-0.000125), "-1.25E-4");
+        assert_eq!(format_num(12345678.5), "1.23456785E7");
+        assert_eq!(format_num(1e15), "1000000000000000");
+        assert_eq!(format_num(1e20), "1.0E20");
+        assert_eq!(format_num(-1e20), "-1.0E20");
+        assert_eq!(format_num(f64::NAN), "NaN");
+        assert_eq!(format_num(f64::INFINITY), "Infinity");
+        assert_eq!(format_num(f64::NEG_INFINITY), "-Infinity");
+    }
+
+    #[test]
+    fn test_value_lenient() {
+        let string = Value::Str(Arc::new("a".into()));
+        assert_eq!(Value::Null.to_num_lenient(), 0.);
+        assert_eq!(string.to_num_lenient(), 1.);
+        assert_eq!(Value::Num(f64::NAN).to_num_lenient(), 0.);
+        assert_eq!(Value::Num(2.5).to_num_lenient(), 2.5);
+        assert!(Value::Null.lenient_eq(&Value::Num(0.)));
+        assert!(Value::Null.lenient_eq(&Value::Null));
+        assert!(string.lenient_eq(&Value::Num(1.)));
+        assert!(!string.lenient_eq(&Value::Str(Arc::new("b".into()))));
+        assert!(Value::Num(0.1 + 0.2).lenient_eq(&Value::Num(0.3)));
+    }
+}
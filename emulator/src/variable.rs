@@ -1,7 +1,7 @@
-use std::cell::UnsafeCell;
+use std::cell::RefCell;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 use crate::building::Building;
 use crate::value::{LazyUtf16String, Value};
 use crate::vm::{VmError, VmResult};
@@ -9,7 +9,7 @@ use crate::vm::{VmError, VmResult};
 #[derive(Debug)]
 pub struct Variable {
     name: String,
-    value: UnsafeCell<Value>,
+    value: RefCell<Value>,
     constant: bool,
 }
 
@@ -17,7 +17,7 @@ impl Variable {
     pub fn new_const(name: String, value: Value, constant: bool) -> Self {
         Variable {
             name,
-            value: UnsafeCell::new(value),
+            value: RefCell::new(value),
             constant,
         }
     }
@@ -31,20 +31,20 @@ impl Variable {
     }
 
     pub fn val(&self) -> Value {
-        unsafe { self.value.as_ref_unchecked() }.clone()
+        self.value.borrow().clone()
     }
 
     pub fn set_val(&self, value: Value) -> VmResult<()> {
         if self.constant {
             Err(VmError::ConstantMutation(self.name.to_string()))
         } else {
-            unsafe { self.value.replace(value) };
+            self.value.replace(value);
             Ok(())
         }
     }
 
     pub fn force_set_val(&self, value: Value) {
-        unsafe { self.value.replace(value) };
+        self.value.replace(value);
     }
 
     pub fn constant(&self) -> bool {
@@ -60,27 +60,27 @@ impl Variable {
     }
 
     pub fn type_name(&self) -> &'static str {
-        unsafe { self.value.as_ref_unchecked() }.type_name()
+        self.value.borrow().type_name()
     }
 
     pub fn is_null(&self) -> bool {
-        unsafe { self.value.as_ref_unchecked() }.is_null()
+        self.value.borrow().is_null()
     }
 
     pub fn as_num(&self) -> VmResult<f64> {
-        unsafe { self.value.as_ref_unchecked() }.as_num()
+        self.value.borrow().as_num()
     }
 
     pub fn as_int(&self) -> VmResult<i64> {
-        unsafe { self.value.as_ref_unchecked() }.as_int()
+        self.value.borrow().as_int()
     }
 
-    pub fn as_str(&self) -> VmResult<Rc<LazyUtf16String>> {
-        unsafe { self.value.as_ref_unchecked() }.as_str()
+    pub fn as_str(&self) -> VmResult<Arc<LazyUtf16String>> {
+        self.value.borrow().as_str()
     }
 
-    pub fn as_building(&self) -> VmResult<Rc<dyn Building>> {
-        unsafe { self.value.as_ref_unchecked() }.as_building()
+    pub fn as_building(&self) -> VmResult<Arc<dyn Building>> {
+        self.value.borrow().as_building()
     }
 }
 
@@ -124,6 +124,19 @@ impl Variables {
         self.by_name.get(name).map(|idx| VarHandle(*idx))
     }
 
+    pub fn len(&self) -> usize {
+        self.variables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    /// Iterates over the variables in the order they were created.
+    pub fn iter(&self) -> impl Iterator<Item = &Variable> {
+        self.variables.iter()
+    }
+
     pub fn insert(&mut self, name: String, var: Variable) -> VarHandle {
         match self.by_name.entry(name) {
             Occupied(entry) => panic!("Double insert: {}", entry.key()),
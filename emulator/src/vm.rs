@@ -1,11 +1,16 @@
-use std::cell::RefCell;
-use std::fmt::{Display, Formatter};
-use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::fmt::{Debug, Display, Formatter};
+use std::io::Write;
+use std::sync::Arc;
 use std::string::ToString;
 use serde::Serialize;
-use crate::building::{Building, ProcessorBuilding};
-use crate::instruction::Instruction;
-use crate::value::{Property, Value};
+use crate::building::{Building, DisplayBuilding, ProcessorBuilding};
+use crate::draw::DrawBuffer;
+use crate::instruction::{CellWrite, ExecuteContext, Instruction, ParseError, SourceSpan};
+use crate::profile::{Profile, Profiler};
+use crate::rng::Rng;
+use crate::trace::Tracer;
+use crate::value::{CoercionMode, Property, Value};
 use crate::variable::{VarHandle, Variable, Variables};
 
 #[derive(Debug)]
@@ -24,10 +29,21 @@ pub enum VmError {
     NoProperty(String, &'static str, &'static str),
     InvalidOperation(String),
     DivisionByZero,
+    Parse(ParseError),
+    DeviceNotFound(String),
+    /// Two linked devices share a name, or a device is named like a builtin variable.
+    DuplicateDevice(String),
+    Trace(std::io::Error),
+    InvalidDeviceContents(String),
+    /// A display is neither a logic display nor a large logic display.
+    InvalidDisplaySize(String, usize),
+    /// An error raised by a building implemented outside the emulator.
+    External(String, String),
 }
 
+/// A runtime error, optionally with the index and source location of the failing instruction.
 #[derive(Debug)]
-pub struct PosVmError(pub VmError, pub Option<usize>);
+pub struct PosVmError(pub VmError, pub Option<(usize, SourceSpan)>);
 
 pub type VmResult<T> = Result<T, VmError>;
 pub type PosVmResult<T> = Result<T, PosVmError>;
@@ -37,8 +53,8 @@ impl VmError {
         PosVmError(self, None)
     }
 
-    pub fn with_pos(self, pos: usize) -> PosVmError {
-        PosVmError(self, Some(pos))
+    pub fn with_pos(self, pos: usize, span: SourceSpan) -> PosVmError {
+        PosVmError(self, Some((pos, span)))
     }
 
     pub fn to_pc_res(self) -> VmError {
@@ -74,6 +90,21 @@ impl VmError {
                 write!(f, "Invalid operation: '{}'", op),
             VmError::DivisionByZero =>
                 write!(f, "Division by zero"),
+            VmError::Parse(err) =>
+                write!(f, "{}", err),
+            VmError::DeviceNotFound(name) =>
+                write!(f, "Device not found: '{}'", name),
+            VmError::DuplicateDevice(name) =>
+                write!(f, "Duplicate device name: '{}'", name),
+            VmError::Trace(err) =>
+                write!(f, "Cannot write trace: {}", err),
+            VmError::InvalidDeviceContents(name) =>
+                write!(f, "Invalid initial contents for device '{}'", name),
+            VmError::InvalidDisplaySize(name, size) =>
+                write!(f, "Invalid size {} for display '{}', must be {} or {}", size, name,
+                       DisplayBuilding::LOGIC_DISPLAY_SIZE, DisplayBuilding::LARGE_LOGIC_DISPLAY_SIZE),
+            VmError::External(name, msg) =>
+                write!(f, "Error in building '{}': {}", name, msg),
         }
     }
 }
@@ -82,6 +113,7 @@ impl Display for VmError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             VmError::PcResError(_) => write!(f, "Error during program counter resolution: ")?,
+            VmError::Parse(_) => {},
             _ => write!(f, "Error: ")?,
         }
         self.print(f)
@@ -91,8 +123,8 @@ impl Display for VmError {
 impl Display for PosVmError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let PosVmError(err, pos) = self;
-        if let Some(pos) = pos {
-            write!(f, "Error at instruction {}: ", pos)?;
+        if let Some((pos, span)) = pos {
+            write!(f, "Error at instruction {} ({}): ", pos, span)?;
             err.print(f)
         } else {
             write!(f, "{}", err)
@@ -104,6 +136,8 @@ impl Display for PosVmError {
 pub struct VmCycleResult {
     pub pc_wrap: bool,
     pub halt: bool,
+    /// The processor used up its instructions for the current tick or started waiting.
+    pub tick_end: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -111,9 +145,13 @@ pub enum VmFinishReason {
     PcWrap,
     Halt,
     InsLimit,
+    /// Also reached when the clock cannot advance past `u64::MAX` ticks.
+    TickLimit,
+    /// Paused by a debugger breakpoint, execution can be resumed.
+    Breakpoint,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct PrintBuffer {
     string: RefCell<String>,
 }
@@ -134,8 +172,22 @@ impl PrintBuffer {
         Ok(())
     }
 
-    pub fn format(&self, _string: &str) -> VmResult<()> {
-        Err(VmError::InvalidFormat("not implemented".to_string()))
+    /// Replaces the lowest-numbered `{0}`..`{9}` placeholder with `string`.
+    /// If there are multiple, the first one is replaced. Does nothing if there is no placeholder.
+    pub fn format(&self, string: &str) -> VmResult<()> {
+        let mut buffer = self.string.borrow_mut();
+        let placeholder = buffer.as_bytes()
+            .windows(3)
+            .enumerate()
+            .filter_map(|(i, w)| match w {
+                [b'{', digit @ b'0'..=b'9', b'}'] => Some((*digit, i)),
+                _ => None,
+            })
+            .min();
+        if let Some((_, i)) = placeholder {
+            buffer.replace_range(i..i + 3, string);
+        }
+        Ok(())
     }
 
     pub fn take(&self) -> String {
@@ -143,13 +195,38 @@ impl PrintBuffer {
     }
 }
 
+/// Notified of the writes into buildings made by the processors it observes,
+/// see `VM::set_write_observer` and `World::set_write_observer`.
+pub trait WriteObserver : Debug + Send + Sync {
+    /// Called after the instruction at `index` of processor `processor` stored a value.
+    fn written(&self, processor: usize, index: usize, span: SourceSpan, write: &CellWrite);
+}
+
+/// A single processor.
+///
+/// A `VM` is `Send` but not `Sync`: it can be moved to another thread, but only one thread runs it at a time.
+/// Only its buildings, which are `Send + Sync`, are shared between threads, so processors linked to the same
+/// devices can run on different threads. `World` runs its processors on the thread that owns it.
 #[derive(Debug)]
 pub struct VM {
     pc_handle: VarHandle,
-    variables: Rc<Variables>,
+    variables: Variables,
+    /// Number of builtin variables, which come before the ones used by the program.
+    builtin_count: usize,
     code: Vec<Instruction>,
+    source_map: Vec<SourceSpan>,
     print_buffer: PrintBuffer,
-    buildings: Vec<Rc<dyn Building>>,
+    draw_buffer: DrawBuffer,
+    buildings: Vec<Arc<dyn Building>>,
+    coercion: CoercionMode,
+    rng: Rng,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    write_observer: Option<(usize, Arc<dyn WriteObserver>)>,
+    ipt: usize,
+    ticks: Cell<u64>,
+    tick_instructions: Cell<usize>,
+    wake_tick: Cell<u64>,
 }
 
 macro_rules! builtin {
@@ -176,14 +253,16 @@ macro_rules! null {
 
 impl VM {
     pub const DEFAULT_CODE_LEN_LIMIT: usize = 1000;
+    pub const DEFAULT_IPT: usize = 1000;
+    pub const TICKS_PER_SECOND: f64 = 60.;
 
-    pub fn new(code: &str, code_len_limit: usize, buildings: Vec<Rc<dyn Building>>) -> VmResult<Self> {
+    pub fn new(code: &str, code_len_limit: usize, buildings: Vec<Arc<dyn Building>>) -> VmResult<Self> {
         let mut vars = Variables::from([
             builtin!("@counter", num!(), false),
-            builtin!("@this", null!()),
+            builtin!("@this", Value::Building(Arc::new(ProcessorBuilding::new("@this".to_string())))),
             builtin!("@thisx", num!()),
             builtin!("@thisy", num!()),
-            builtin!("@ipt", num!(1000.)),
+            builtin!("@ipt", num!(Self::DEFAULT_IPT as f64)),
             builtin!("@timescale", num!(1.)),
             builtin!("@links", num!(buildings.len() as f64)),
             builtin!("@unit", null!(), false),
@@ -213,28 +292,43 @@ impl VM {
                 var_name, Value::Property(Property::new(name)), true));
         }
         for building in &buildings {
+            if vars.get_handle(building.name()).is_some() {
+                return Err(VmError::DuplicateDevice(building.name().to_string()));
+            }
             vars.insert(building.name().to_string(),
                         Variable::new_const(building.name().to_string(),
                                             Value::Building(building.clone()), true));
         }
-        let code = code.split("\n")
-            .filter_map(|ln| Instruction::parse(ln, &mut vars)).collect::<Vec<_>>();
+        let builtin_count = vars.len();
+        let (code, source_map): (Vec<_>, Vec<_>) = Instruction::parse_program(code, &mut vars)
+            .map_err(VmError::Parse)?
+            .into_iter()
+            .unzip();
         if code.is_empty() {
             return Err(VmError::EmptyCode);
         }
         if code.len() > code_len_limit {
             return Err(VmError::CodeTooLong(code.len(), code_len_limit));
         }
-        let vm = VM {
+        Ok(VM {
             pc_handle: vars.get_handle("@counter").unwrap(),
-            variables: Rc::new(vars),
+            variables: vars,
+            builtin_count,
             code,
+            source_map,
             print_buffer: PrintBuffer::new(),
+            draw_buffer: DrawBuffer::new(),
             buildings,
-        };
-        vm.variables.get_handle("@this").unwrap().force_set(&vm.variables, Value::Building(
-            Rc::new(ProcessorBuilding::new("@this".to_string(), Rc::downgrade(&vm.variables)))));
-        Ok(vm)
+            coercion: CoercionMode::default(),
+            rng: Rng::new(Rng::random_seed()),
+            tracer: None,
+            profiler: None,
+            write_observer: None,
+            ipt: Self::DEFAULT_IPT,
+            ticks: Cell::new(0),
+            tick_instructions: Cell::new(0),
+            wake_tick: Cell::new(0),
+        })
     }
 
     pub fn get_val(&self, name: &str) -> VmResult<Value> {
@@ -243,6 +337,122 @@ impl VM {
             .map(|h| h.val(&self.variables).clone())
     }
 
+    /// Sets a variable used by the program, constants cannot be changed.
+    pub fn set_var(&self, name: &str, value: Value) -> VmResult<()> {
+        self.variables.get_handle(name)
+            .ok_or_else(|| VmError::VariableNotFound(name.to_string()))?
+            .set(&self.variables, value)
+    }
+
+    /// Reads from a building like `read` executed by this processor, so `@this` is this processor.
+    pub fn read_building(&self, building: &dyn Building, index: Value) -> VmResult<Value> {
+        building.read_as(&self.variables, index)
+    }
+
+    /// Writes into a building like `write` executed by this processor, see `read_building`.
+    pub fn write_building(&self, building: &dyn Building, index: Value, value: Value) -> VmResult<()> {
+        building.write_as(&self.variables, index, value).map(|_| ())
+    }
+
+    /// Returns the variables used by the program, excluding builtins and linked buildings.
+    pub fn user_variables(&self) -> Vec<(String, Value)> {
+        self.variables.iter()
+            .skip(self.builtin_count)
+            .map(|var| (var.name().to_string(), var.val()))
+            .collect()
+    }
+
+    pub fn set_coercion_mode(&mut self, mode: CoercionMode) {
+        self.coercion = mode;
+    }
+
+    /// Restarts the random number generator used by `op rand` with the given seed.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.rng.seed()
+    }
+
+    /// Streams a JSONL record of every executed instruction to `writer`, or stops tracing if `None`.
+    pub fn set_trace(&mut self, writer: Option<Box<dyn Write + Send>>) {
+        self.tracer = writer.map(Tracer::new);
+    }
+
+    /// Starts counting executions of every instruction, or stops if `enabled` is false.
+    /// Counts are reset either way.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler = enabled.then(|| Profiler::new(self.code.len()));
+    }
+
+    /// Reports every value this processor stores into a building to `observer`,
+    /// identifying the processor as `processor`. Replaces any previous observer.
+    pub fn set_write_observer(&mut self, processor: usize, observer: Option<Arc<dyn WriteObserver>>) {
+        self.write_observer = observer.map(|observer| (processor, observer));
+    }
+
+    /// Returns the counts collected since profiling was enabled.
+    pub fn profile(&self) -> Option<Profile> {
+        self.profiler.as_ref().map(|profiler| profiler.profile(&self.code, &self.source_map))
+    }
+
+    /// Sets the number of instructions executed per tick.
+    pub fn set_ipt(&mut self, ipt: usize) {
+        self.ipt = ipt.max(1);
+        self.variables.get_handle("@ipt").unwrap().force_set(&self.variables, num!(self.ipt as f64));
+    }
+
+    pub fn ipt(&self) -> usize {
+        self.ipt
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.get()
+    }
+
+    /// Moves the simulated clock to `ticks` and updates the time builtins.
+    pub fn set_ticks(&self, ticks: u64) {
+        self.ticks.set(ticks);
+        let ticks = ticks as f64;
+        for (name, value) in [
+            ("@tick", ticks),
+            ("@time", ticks * 1000. / Self::TICKS_PER_SECOND),
+            ("@second", ticks / Self::TICKS_PER_SECOND),
+            ("@minute", ticks / Self::TICKS_PER_SECOND / 60.),
+        ] {
+            self.variables.get_handle(name).unwrap().force_set(&self.variables, num!(value));
+        }
+    }
+
+    /// Returns the first tick at which the processor is no longer suspended by `wait`.
+    pub fn wake_tick(&self) -> u64 {
+        self.wake_tick.get()
+    }
+
+    /// Returns the index of the next instruction, if the program counter holds a valid one.
+    pub fn pc(&self) -> Option<usize> {
+        self.pc_handle.get(&self.variables).as_int().ok()
+            .and_then(|pc| usize::try_from(pc).ok())
+            .filter(|pc| *pc < self.code.len())
+    }
+
+    pub fn instruction(&self, index: usize) -> Option<&Instruction> {
+        self.code.get(index)
+    }
+
+    /// Evaluates the input operands of the instruction at `index` with the current variable values.
+    pub fn instruction_inputs(&self, index: usize) -> Option<VmResult<Vec<Value>>> {
+        self.code.get(index).map(|ins| ins.inputs().into_iter()
+            .map(|arg| arg.eval(&self.variables))
+            .collect())
+    }
+
+    /// Returns the source location of the instruction at `index`.
+    pub fn source_span(&self, index: usize) -> Option<SourceSpan> {
+        self.source_map.get(index).copied()
+    }
+
     pub fn cycle(&self) -> PosVmResult<VmCycleResult> {
         let pc = match self.pc_handle.get(&self.variables).as_int() {
             Ok(pc) => pc,
@@ -261,29 +471,328 @@ impl VM {
             new_pc => (new_pc, false),
         };
         self.pc_handle.set(&self.variables, num!(new_pc as f64)).unwrap();
-        match self.code[pc].execute(&self.variables, &self.print_buffer,
-                                    &self.buildings, self.pc_handle) {
-            Ok(res) => Ok(VmCycleResult {
-                pc_wrap,
-                halt: res.halt,
-            }),
-            Err(err) => Err(err.with_pos(pc)),
+        let inputs = self.tracer.as_ref().map(|_| self.instruction_inputs(pc).unwrap());
+        let res = self.code[pc].execute(&ExecuteContext {
+            vars: &self.variables,
+            print_buffer: &self.print_buffer,
+            draw_buffer: &self.draw_buffer,
+            buildings: &self.buildings,
+            pc: self.pc_handle,
+            coercion: self.coercion,
+            rng: &self.rng,
+        });
+        let res = match (&self.tracer, inputs) {
+            (Some(tracer), Some(inputs)) => {
+                let traced = inputs.and_then(|inputs|
+                    tracer.record(pc, self.source_map[pc], &self.code[pc], &inputs, &res, &self.variables));
+                // the error of the instruction takes precedence over failing to trace it
+                res.and_then(|res| traced.map(|()| res))
+            },
+            _ => res,
+        };
+        if let Some(profiler) = &self.profiler {
+            // a failing instruction was executed as well
+            profiler.record(pc, res.as_ref().is_ok_and(|res| res.jumped));
+        }
+        match res {
+            Ok(res) => {
+                if let (Some((processor, observer)), Some(write)) = (&self.write_observer, &res.written) {
+                    observer.written(*processor, pc, self.source_map[pc], write);
+                }
+                let mut tick_end = false;
+                if let Some(time) = res.wait.filter(|time| *time > 0.) {
+                    let ticks = (time * Self::TICKS_PER_SECOND).ceil().min(u64::MAX as f64) as u64;
+                    self.wake_tick.set(self.ticks().saturating_add(ticks));
+                    tick_end = true;
+                }
+                let tick_instructions = self.tick_instructions.get() + 1;
+                if tick_end || tick_instructions >= self.ipt {
+                    self.tick_instructions.set(0);
+                    tick_end = true;
+                } else {
+                    self.tick_instructions.set(tick_instructions);
+                }
+                Ok(VmCycleResult {
+                    pc_wrap,
+                    halt: res.halt,
+                    tick_end,
+                })
+            },
+            Err(err) => Err(err.with_pos(pc, self.source_map[pc])),
+        }
+    }
+
+    /// Executes a single instruction of a processor running alone, advancing the clock
+    /// at the end of each tick. Returns the reason to finish the run, if any.
+    pub fn step(&self, tick_limit: Option<u64>, end_on_wrap: bool) -> PosVmResult<Option<VmFinishReason>> {
+        let res = self.cycle()?;
+        if res.halt {
+            return Ok(Some(VmFinishReason::Halt));
+        } else if res.pc_wrap && end_on_wrap {
+            return Ok(Some(VmFinishReason::PcWrap));
         }
+        if res.tick_end {
+            let limit = tick_limit.unwrap_or(u64::MAX);
+            let next = self.wake_tick().max(self.ticks().saturating_add(1));
+            self.set_ticks(next.min(limit));
+            if self.ticks() >= limit {
+                return Ok(Some(VmFinishReason::TickLimit));
+            }
+        }
+        Ok(None)
     }
 
-    pub fn run(&self, limit: Option<usize>, end_on_wrap: bool) -> PosVmResult<VmFinishReason> {
+    /// Runs the processor alone, advancing the clock at the end of each tick.
+    /// Time spent waiting is skipped over.
+    /// The trace, if enabled, is flushed when the run finishes.
+    pub fn run(&self, limit: Option<usize>, tick_limit: Option<u64>,
+               end_on_wrap: bool) -> PosVmResult<VmFinishReason> {
+        let res = self.run_steps(limit, tick_limit, end_on_wrap);
+        if let Some(tracer) = &self.tracer {
+            let flushed = tracer.flush().map_err(VmError::to_pos);
+            return res.and_then(|reason| flushed.map(|_| reason));
+        }
+        res
+    }
+
+    fn run_steps(&self, limit: Option<usize>, tick_limit: Option<u64>,
+                 end_on_wrap: bool) -> PosVmResult<VmFinishReason> {
         for _ in 0..limit.unwrap_or(usize::MAX) {
-            let res = self.cycle()?;
-            if res.halt {
-                return Ok(VmFinishReason::Halt);
-            } else if res.pc_wrap && end_on_wrap {
-                return Ok(VmFinishReason::PcWrap);
+            if let Some(reason) = self.step(tick_limit, end_on_wrap)? {
+                return Ok(reason);
             }
         }
         Ok(VmFinishReason::InsLimit)
     }
 
+    pub fn print_buffer(&self) -> &PrintBuffer {
+        &self.print_buffer
+    }
+
     pub fn into_print_buffer(self) -> PrintBuffer {
         self.print_buffer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_coercion_mode() {
+        let code = "op add x y 1\njump 3 equal z 0\nstop\nprint x";
+        let vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        assert!(matches!(vm.run(Some(10), None, true), Err(PosVmError(VmError::InvalidCast(..), Some((0, _))))));
+
+        let mut vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        vm.set_coercion_mode(CoercionMode::Lenient);
+        assert!(matches!(vm.run(Some(10), None, true), Ok(VmFinishReason::PcWrap)));
+        assert_eq!(vm.into_print_buffer().take(), "1");
+
+        let cell = Arc::new(crate::building::MemoryBuilding::new("cell1".to_string(), 4));
+        let code = "set x 1.5\nwrite 3 cell1 x\nread y cell1 x\ngetlink z x\nstop";
+        let vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![cell.clone()]).unwrap();
+        assert!(matches!(vm.run(None, None, false), Err(PosVmError(VmError::InvalidCast(..), Some((1, _))))));
+
+        let message = Arc::new(crate::building::MessageBuilding::new("message1".to_string()));
+        let mut vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![cell.clone(), message]).unwrap();
+        vm.set_coercion_mode(CoercionMode::Lenient);
+        assert!(matches!(vm.run(None, None, false), Ok(VmFinishReason::Halt)));
+        assert_eq!(*cell.get_data(), [0., 3., 0., 0.]);
+        assert_eq!(vm.get_val("y").unwrap(), Value::Num(3.));
+        assert!(matches!(vm.get_val("z").unwrap(), Value::Building(b) if b.name() == "message1"));
+    }
+
+    #[test]
+    fn test_vm_user_variables() {
+        use crate::value::LazyUtf16String;
+
+        let vm = VM::new("set x 1\nset y \"a\"\nop add @counter x @tick\nset z null",
+                         VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        vm.run(Some(3), None, false).unwrap();
+        assert_eq!(vm.user_variables(), [
+            ("x".to_string(), Value::Num(1.)),
+            ("y".to_string(), Value::Str(Arc::new(LazyUtf16String::new(Arc::new("a".to_string()))))),
+            ("z".to_string(), Value::Null),
+        ]);
+    }
+
+    #[test]
+    fn test_vm_seed() {
+        let run = |seed| {
+            let mut vm = VM::new("op rand x 100 0\nprint x\nprint \" \"", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+            vm.set_seed(seed);
+            vm.run(Some(30), None, false).unwrap();
+            vm.into_print_buffer().take()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn test_vm_trace() {
+        use std::sync::Mutex;
+        use crate::building::MemoryBuilding;
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let cell = Arc::new(MemoryBuilding::new("cell1".to_string(), 4));
+        let code = "set x 2\nop mul y x 1.5\nwrite y cell1 1\nstop";
+        let mut vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![cell.clone()]).unwrap();
+        let trace = Shared::default();
+        vm.set_trace(Some(Box::new(trace.clone())));
+        assert!(matches!(vm.run(None, None, false), Ok(VmFinishReason::Halt)));
+        let trace = String::from_utf8(std::mem::take(&mut trace.0.lock().unwrap())).unwrap();
+        let lines = trace.lines().collect::<Vec<_>>();
+        assert_eq!(lines, [
+            r#"{"index":0,"line":1,"opcode":"set","inputs":[2.0],"written":{"type":"variable","name":"x","value":2.0}}"#,
+            r#"{"index":1,"line":2,"opcode":"op","inputs":[2.0,1.5],"written":{"type":"variable","name":"y","value":3.0}}"#,
+            r#"{"index":2,"line":3,"opcode":"write","inputs":[3.0,"cell1",1.0],"written":{"type":"cell","building":"cell1","index":1.0,"value":3.0}}"#,
+            r#"{"index":3,"line":4,"opcode":"stop","inputs":[],"written":null}"#,
+        ]);
+
+        // the value passed to the write is logged, and the failing instruction is recorded
+        let code = "write \"a\" cell1 0\nwrite 2 @this \"x\"\nwrite 1 cell1 4\nset x 1";
+        let mut vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![cell]).unwrap();
+        vm.set_coercion_mode(CoercionMode::Lenient);
+        let trace = Shared::default();
+        vm.set_trace(Some(Box::new(trace.clone())));
+        assert!(matches!(vm.run(None, None, false), Err(PosVmError(VmError::IndexTooHigh(..), Some((2, _))))));
+        let trace = String::from_utf8(std::mem::take(&mut trace.0.lock().unwrap())).unwrap();
+        let lines = trace.lines().collect::<Vec<_>>();
+        assert_eq!(lines, [
+            r#"{"index":0,"line":1,"opcode":"write","inputs":["a","cell1",0.0],"written":{"type":"cell","building":"cell1","index":0.0,"value":1.0}}"#,
+            r#"{"index":1,"line":2,"opcode":"write","inputs":[2.0,"@this","x"],"written":{"type":"cell","building":"@this","index":"x","value":2.0}}"#,
+            r#"{"index":2,"line":3,"opcode":"write","inputs":[1.0,"cell1",4.0],"written":null,"error":"Error: Index out of range (4 >= 4) for memory cell"}"#,
+        ]);
+    }
+
+    #[test]
+    fn test_vm_profile() {
+        let mut vm = VM::new("set i 0\nop add i i 1\njump 1 lessThan i 10\nstop", VM::DEFAULT_CODE_LEN_LIMIT, vec![])
+            .unwrap();
+        vm.set_profiling(true);
+        assert!(matches!(vm.run(None, None, false), Ok(VmFinishReason::Halt)));
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.total, 22);
+        assert_eq!(profile.instructions.iter().map(|p| p.executions).collect::<Vec<_>>(), [1, 10, 10, 1]);
+        assert_eq!(profile.instructions[2].jumps_taken, Some(9));
+        assert_eq!(profile.instructions[1].jumps_taken, None);
+        let report = profile.report();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "22 instructions executed");
+        assert_eq!(lines[2], "     1      2          10  45.45%  op");
+        assert_eq!(lines[3], "     2      3          10  45.45%  jump (taken 9/10)");
+    }
+
+    #[test]
+    fn test_vm_clock() {
+        let mut vm = VM::new("set n 1\nwait 0.5\nprint @tick", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        vm.set_ipt(2);
+        assert!(matches!(vm.run(Some(6), None, false), Ok(VmFinishReason::InsLimit)));
+        // first tick ends with the wait, the second after two more instructions, then another wait
+        assert_eq!(vm.ticks(), 30 + 1 + 30);
+        assert_eq!(vm.into_print_buffer().take(), "3061");
+
+        let mut vm = VM::new("op add n n 1", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        vm.set_coercion_mode(CoercionMode::Lenient);
+        assert!(matches!(vm.run(None, Some(3), false), Ok(VmFinishReason::TickLimit)));
+        assert_eq!(vm.get_val("n").unwrap(), Value::Num(3000.));
+        assert_eq!(vm.get_val("@second").unwrap(), Value::Num(0.05));
+    }
+
+    #[test]
+    fn test_vm_wait_forever() {
+        let vm = VM::new("wait 1e300\nset x 1", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        assert!(matches!(vm.run(Some(10), None, false), Ok(VmFinishReason::TickLimit)));
+        assert_eq!(vm.ticks(), u64::MAX);
+        assert_eq!(vm.get_val("x").unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn test_vm_draw_defaults() {
+        use crate::color::Color;
+
+        let display = Arc::new(DisplayBuilding::new("display1".to_string(), DisplayBuilding::LOGIC_DISPLAY_SIZE));
+        let code = "draw color 255 0 0\ndraw rect 1 1 1\ndraw print\ndrawflush display1";
+        let vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![display.clone()]).unwrap();
+        assert!(matches!(vm.run(None, None, true), Ok(VmFinishReason::PcWrap)));
+        let framebuffer = display.get_framebuffer();
+        // a rectangle without height covers no pixel
+        assert_eq!(framebuffer.pixel(1, 1), crate::draw::Framebuffer::BACKGROUND);
+
+        let code = "draw color 255 0 0\ndraw rect 1 1 1 1\ndrawflush display1";
+        let vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![display.clone()]).unwrap();
+        assert!(matches!(vm.run(None, None, true), Ok(VmFinishReason::PcWrap)));
+        assert_eq!(display.get_framebuffer().pixel(1, 1), Color { r: 255, g: 0, b: 0, a: 255 });
+    }
+
+    #[test]
+    fn test_print_buffer_format() {
+        let buffer = PrintBuffer::new();
+        buffer.write("{1}/{0} {0} {x} {10}");
+        buffer.format("a").unwrap();
+        buffer.format("b").unwrap();
+        buffer.format("c").unwrap();
+        buffer.format("d").unwrap();
+        assert_eq!(buffer.take(), "c/a b {x} {10}");
+        buffer.format("a").unwrap();
+        assert_eq!(buffer.take(), "");
+    }
+
+    #[test]
+    fn test_vm_this() {
+        let code = "set x 1\nwrite 5 @this \"x\"\nread y @this \"x\"\nstop";
+        let vm = VM::new(code, VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        assert!(matches!(vm.run(None, None, false), Ok(VmFinishReason::Halt)));
+        assert_eq!(vm.get_val("y").unwrap(), Value::Num(5.));
+        let this = vm.get_val("@this").unwrap().as_building().unwrap();
+        vm.write_building(&*this, Value::Str(Arc::new("x".into())), Value::Num(6.)).unwrap();
+        assert_eq!(vm.read_building(&*this, Value::Str(Arc::new("x".into()))).unwrap(), Value::Num(6.));
+
+        let vm = VM::new("read y @this \"missing\"", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap();
+        assert!(matches!(vm.run(None, None, false), Err(PosVmError(VmError::VariableNotFound(_), _))));
+    }
+
+    #[test]
+    fn test_vm_duplicate_device() {
+        use crate::building::MemoryBuilding;
+
+        let cell = || Arc::new(MemoryBuilding::new("cell1".to_string(), 4)) as Arc<dyn Building>;
+        let res = VM::new("stop", VM::DEFAULT_CODE_LEN_LIMIT, vec![cell(), cell()]);
+        assert!(matches!(res, Err(VmError::DuplicateDevice(name)) if name == "cell1"));
+        let counter = Arc::new(MemoryBuilding::new("@counter".to_string(), 4));
+        let res = VM::new("stop", VM::DEFAULT_CODE_LEN_LIMIT, vec![counter]);
+        assert!(matches!(res, Err(VmError::DuplicateDevice(name)) if name == "@counter"));
+    }
+
+    #[test]
+    fn test_vm_send() {
+        use crate::building::MemoryBuilding;
+
+        let cell = Arc::new(MemoryBuilding::new("cell1".to_string(), 4));
+        let handles = (0..4).map(|i| {
+            let code = format!("write {i} cell1 {i}\nstop");
+            let vm = VM::new(&code, VM::DEFAULT_CODE_LEN_LIMIT, vec![cell.clone()]).unwrap();
+            std::thread::spawn(move || matches!(vm.run(None, None, false), Ok(VmFinishReason::Halt)))
+        }).collect::<Vec<_>>();
+        assert!(handles.into_iter().all(|handle| handle.join().unwrap()));
+        assert_eq!(*cell.get_data(), [0., 1., 2., 3.]);
+
+        // a whole world can also be moved to another thread
+        let mut world = crate::world::World::new();
+        world.add_processor(VM::new("write 9 cell1 0\nstop", VM::DEFAULT_CODE_LEN_LIMIT, vec![cell.clone()]).unwrap());
+        std::thread::spawn(move || world.run(None, None, false)).join().unwrap();
+        assert_eq!(cell.get_data()[0], 9.);
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use crate::vm::{PosVmResult, VmFinishReason, WriteObserver, VM};
+
+#[derive(Debug)]
+struct Processor {
+    vm: VM,
+    executed: usize,
+    result: Option<PosVmResult<VmFinishReason>>,
+}
+
+/// Several processors sharing the same buildings, run tick by tick.
+///
+/// In every tick the processors are run in the order they were added,
+/// each until it uses up its instructions per tick, starts waiting or finishes.
+/// All of them run on the thread that owns the world, which can be moved to another thread like a `VM`.
+#[derive(Debug, Default)]
+pub struct World {
+    processors: Vec<Processor>,
+    ticks: u64,
+    write_observer: Option<Arc<dyn WriteObserver>>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a processor and returns its index.
+    pub fn add_processor(&mut self, mut vm: VM) -> usize {
+        vm.set_ticks(self.ticks);
+        if let Some(observer) = &self.write_observer {
+            vm.set_write_observer(self.processors.len(), Some(observer.clone()));
+        }
+        self.processors.push(Processor {
+            vm,
+            executed: 0,
+            result: None,
+        });
+        self.processors.len() - 1
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Reports the values stored into buildings by every processor, including ones added later,
+    /// to `observer`. Processors are identified by their index.
+    pub fn set_write_observer(&mut self, observer: Option<Arc<dyn WriteObserver>>) {
+        for (i, p) in self.processors.iter_mut().enumerate() {
+            p.vm.set_write_observer(i, observer.clone());
+        }
+        self.write_observer = observer;
+    }
+
+    pub fn processor(&self, index: usize) -> Option<&VM> {
+        self.processors.get(index).map(|p| &p.vm)
+    }
+
+    /// Returns whether any processor has not finished yet.
+    pub fn running(&self) -> bool {
+        self.processors.iter().any(|p| p.result.is_none())
+    }
+
+    /// Runs a single tick. `limit` is the instruction limit of each processor.
+    pub fn tick(&mut self, limit: Option<usize>, end_on_wrap: bool) {
+        let ticks = self.ticks;
+        for p in self.processors.iter_mut().filter(|p| p.result.is_none()) {
+            if p.vm.wake_tick() > ticks {
+                continue;
+            }
+            p.vm.set_ticks(ticks);
+            p.result = loop {
+                if limit.is_some_and(|limit| p.executed >= limit) {
+                    break Some(Ok(VmFinishReason::InsLimit));
+                }
+                p.executed += 1;
+                match p.vm.cycle() {
+                    Err(err) => break Some(Err(err)),
+                    Ok(res) if res.halt => break Some(Ok(VmFinishReason::Halt)),
+                    Ok(res) if res.pc_wrap && end_on_wrap => break Some(Ok(VmFinishReason::PcWrap)),
+                    Ok(res) if res.tick_end => break None,
+                    Ok(_) => {},
+                }
+            };
+        }
+        // skip ticks in which every processor is waiting
+        let next = self.processors.iter()
+            .filter(|p| p.result.is_none())
+            .map(|p| p.vm.wake_tick())
+            .min()
+            .unwrap_or(0);
+        self.ticks = next.max(ticks.saturating_add(1));
+    }
+
+    /// Runs until every processor finishes or `tick_limit` is reached.
+    /// The clock stops at `u64::MAX` ticks, which is also a tick limit.
+    pub fn run(&mut self, limit: Option<usize>, tick_limit: Option<u64>, end_on_wrap: bool) {
+        let tick_limit = tick_limit.unwrap_or(u64::MAX);
+        while self.running() {
+            if self.ticks >= tick_limit {
+                for p in self.processors.iter_mut().filter(|p| p.result.is_none()) {
+                    p.result = Some(Ok(VmFinishReason::TickLimit));
+                }
+                break;
+            }
+            self.tick(limit, end_on_wrap);
+            self.ticks = self.ticks.min(tick_limit);
+        }
+        for p in &self.processors {
+            p.vm.set_ticks(self.ticks);
+        }
+    }
+
+    /// Returns the processors along with their results.
+    /// Processors that have not finished yet have no result.
+    pub fn into_results(self) -> Vec<(VM, Option<PosVmResult<VmFinishReason>>)> {
+        self.processors.into_iter().map(|p| (p.vm, p.result)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::building::{Building, MemoryBuilding};
+    use crate::debugger::CellWatcher;
+    use crate::value::Value;
+    use super::*;
+
+    #[test]
+    fn test_world_shared_memory() {
+        let cell = Arc::new(MemoryBuilding::new("cell1".to_string(), 4));
+        let links = || vec![cell.clone() as Arc<dyn Building>];
+        let mut world = World::new();
+        let mut writer = VM::new("read n cell1 0\nop add n n 1\nwrite n cell1 0\nwait 1",
+                                 VM::DEFAULT_CODE_LEN_LIMIT, links()).unwrap();
+        writer.set_ipt(4);
+        let mut reader = VM::new("read n cell1 0\njump 0 lessThan n 3\nprint n\nstop",
+                                 VM::DEFAULT_CODE_LEN_LIMIT, links()).unwrap();
+        reader.set_ipt(2);
+        world.add_processor(writer);
+        world.add_processor(reader);
+        world.run(None, Some(1000), false);
+
+        // the writer increments the cell once per second, at ticks 0, 60 and 120
+        assert_eq!(world.ticks(), 1000);
+        let mut results = world.into_results().into_iter();
+        let (writer, result) = results.next().unwrap();
+        assert!(matches!(result, Some(Ok(VmFinishReason::TickLimit))));
+        assert_eq!(writer.get_val("n").unwrap(), Value::Num(17.));
+        let (reader, result) = results.next().unwrap();
+        assert!(matches!(result, Some(Ok(VmFinishReason::Halt))));
+        assert_eq!(reader.get_val("@tick").unwrap(), Value::Num(1000.));
+        assert_eq!(reader.into_print_buffer().take(), "3");
+    }
+
+    #[test]
+    fn test_world_wait_forever() {
+        let mut world = World::new();
+        world.add_processor(VM::new("wait 1e300\nset x 1", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap());
+        world.add_processor(VM::new("stop", VM::DEFAULT_CODE_LEN_LIMIT, vec![]).unwrap());
+        world.run(Some(10), None, false);
+
+        assert_eq!(world.ticks(), u64::MAX);
+        let mut results = world.into_results().into_iter();
+        let (waiting, result) = results.next().unwrap();
+        assert!(matches!(result, Some(Ok(VmFinishReason::TickLimit))));
+        assert_eq!(waiting.get_val("x").unwrap(), Value::Null);
+        assert!(matches!(results.next().unwrap().1, Some(Ok(VmFinishReason::Halt))));
+    }
+
+    #[test]
+    fn test_world_watch_cell() {
+        let cell = Arc::new(MemoryBuilding::new("cell1".to_string(), 4));
+        let links = || vec![cell.clone() as Arc<dyn Building>];
+        let mut world = World::new();
+        world.add_processor(VM::new("write 1 cell1 0\nstop", VM::DEFAULT_CODE_LEN_LIMIT, links()).unwrap());
+        let watcher = Arc::new(CellWatcher::new());
+        watcher.watch(7, "cell1".to_string(), 0);
+        world.set_write_observer(Some(watcher.clone()));
+        // added after the observer, which still sees its writes
+        world.add_processor(VM::new("write 2 cell1 1\nwrite 3 cell1 0\nstop", VM::DEFAULT_CODE_LEN_LIMIT, links()).unwrap());
+        world.run(None, Some(10), false);
+
+        let events = watcher.take_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].watchpoint, events[0].processor, events[0].index), (7, 0, 0));
+        assert_eq!((&events[0].old, &events[0].new), (&Some(Value::Num(0.)), &Value::Num(1.)));
+        assert_eq!((events[1].processor, events[1].index, events[1].span.line), (1, 1, 2));
+        assert_eq!((&events[1].old, &events[1].new), (&Some(Value::Num(1.)), &Value::Num(3.)));
+        assert!(watcher.take_events().is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use pyo3::create_exception;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use emulator::vm::{PosVmError, VmError};
+
+create_exception!(mlog_emulator, EmulatorError, PyRuntimeError,
+    "Base class of the errors of the emulator. Every error has the attributes `index`, `line`, \
+     `start_column` and `end_column` of the failing instruction, which are `None` if unknown.");
+
+macro_rules! errors {
+    ($($name:ident),* $(,)?) => {
+        $(create_exception!(mlog_emulator, $name, EmulatorError);)*
+
+        pub fn register(m: &Bound<'_, PyModule>) -> PyResult<()> {
+            m.add("EmulatorError", m.py().get_type::<EmulatorError>())?;
+            $(m.add(stringify!($name), m.py().get_type::<$name>())?;)*
+            Ok(())
+        }
+    };
+}
+
+errors!(
+    InvalidCastError,
+    InvalidBuildingTypeError,
+    VariableNotFoundError,
+    ConstantMutationError,
+    EmptyCodeError,
+    CodeTooLongError,
+    InvalidCharacterError,
+    NegativeIndexError,
+    IndexTooHighError,
+    ProgramCounterError,
+    InvalidFormatError,
+    NoPropertyError,
+    InvalidOperationError,
+    DivisionByZeroError,
+    ParseError,
+    DeviceNotFoundError,
+    DuplicateDeviceError,
+    TraceError,
+    InvalidDeviceContentsError,
+    InvalidDisplaySizeError,
+    ExternalError,
+);
+
+/// Creates the exception of an error, without position.
+/// The attributes specific to each kind of error are set from its fields.
+fn vm_error(py: Python, error: &VmError) -> PyResult<PyErr> {
+    let msg = error.to_string();
+    let (err, fields): (PyErr, Vec<(&str, PyObject)>) = match error {
+        VmError::InvalidCast(value, from, to) => (InvalidCastError::new_err(msg), vec![
+            ("value", value.into_pyobject(py)?.into_any().unbind()),
+            ("from_type", from.into_pyobject(py)?.into_any().unbind()),
+            ("to_type", to.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::InvalidBuildingType(action, device) => (InvalidBuildingTypeError::new_err(msg), vec![
+            ("action", action.into_pyobject(py)?.into_any().unbind()),
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::VariableNotFound(name) => (VariableNotFoundError::new_err(msg), vec![
+            ("variable", name.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::ConstantMutation(name) => (ConstantMutationError::new_err(msg), vec![
+            ("variable", name.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::EmptyCode => (EmptyCodeError::new_err(msg), vec![]),
+        VmError::CodeTooLong(len, limit) => (CodeTooLongError::new_err(msg), vec![
+            ("length", len.into_pyobject(py)?.into_any().unbind()),
+            ("limit", limit.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::InvalidCharacter(ch) => (InvalidCharacterError::new_err(msg), vec![
+            ("value", ch.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::NegativeIndex(index, device) => (NegativeIndexError::new_err(msg), vec![
+            ("value", index.into_pyobject(py)?.into_any().unbind()),
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::IndexTooHigh(index, limit, device) => (IndexTooHighError::new_err(msg), vec![
+            ("value", index.into_pyobject(py)?.into_any().unbind()),
+            ("limit", limit.into_pyobject(py)?.into_any().unbind()),
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::PcResError(cause) => {
+            let err = ProgramCounterError::new_err(msg);
+            err.set_cause(py, Some(vm_error(py, cause)?));
+            (err, vec![])
+        },
+        VmError::InvalidFormat(_) => (InvalidFormatError::new_err(msg), vec![]),
+        VmError::NoProperty(value, type_name, property) => (NoPropertyError::new_err(msg), vec![
+            ("value", value.into_pyobject(py)?.into_any().unbind()),
+            ("type_name", type_name.into_pyobject(py)?.into_any().unbind()),
+            ("property", property.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::InvalidOperation(op) => (InvalidOperationError::new_err(msg), vec![
+            ("operation", op.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::DivisionByZero => (DivisionByZeroError::new_err(msg), vec![]),
+        VmError::Parse(err) => (ParseError::new_err(msg), vec![
+            ("line", err.line.into_pyobject(py)?.into_any().unbind()),
+            ("column", err.column.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::DeviceNotFound(device) => (DeviceNotFoundError::new_err(msg), vec![
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::DuplicateDevice(device) => (DuplicateDeviceError::new_err(msg), vec![
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::Trace(_) => (TraceError::new_err(msg), vec![]),
+        VmError::InvalidDeviceContents(device) => (InvalidDeviceContentsError::new_err(msg), vec![
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::InvalidDisplaySize(device, size) => (InvalidDisplaySizeError::new_err(msg), vec![
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+            ("size", size.into_pyobject(py)?.into_any().unbind()),
+        ]),
+        VmError::External(device, _) => (ExternalError::new_err(msg), vec![
+            ("device", device.into_pyobject(py)?.into_any().unbind()),
+        ]),
+    };
+    let value = err.value(py);
+    for name in ["index", "line", "start_column", "end_column"] {
+        value.setattr(name, py.None())?;
+    }
+    for (name, field) in fields {
+        value.setattr(name, field)?;
+    }
+    Ok(err)
+}
+
+/// Converts an error to the exception of its kind, with the position of the failing instruction.
+pub fn to_py_err(error: &PosVmError) -> PyErr {
+    Python::with_gil(|py| {
+        let PosVmError(inner, pos) = error;
+        let err = vm_error(py, inner)?;
+        if let Some((index, span)) = pos {
+            let value = err.value(py);
+            value.setattr("index", index)?;
+            value.setattr("line", span.line)?;
+            value.setattr("start_column", span.start_column)?;
+            value.setattr("end_column", span.end_column)?;
+            value.setattr("args", (error.to_string(),))?;
+        }
+        Ok(err)
+    }).unwrap_or_else(|err| err)
+}
+
+/// Sets an attribute telling which of several runs failed, `None` if the error concerns all of them.
+pub fn with_run_index(err: PyErr, attr: &str, index: Option<usize>) -> PyErr {
+    Python::with_gil(|py| err.value(py).setattr(attr, index).map(|()| err)).unwrap_or_else(|err| err)
+}
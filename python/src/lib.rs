@@ -1,14 +1,38 @@
-use std::collections::HashMap;
+mod errors;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+use emulator::building::Building;
 use emulator::interface;
 use emulator::interface::Output;
-use emulator::vm::VmFinishReason;
+use emulator::{coverage, profile};
+use emulator::rng::Rng;
+use emulator::value::{LazyUtf16String, Property, Value};
+use emulator::vm::{VmError, VmFinishReason, VmResult, VM};
 
 #[pyclass]
 #[derive(Debug, Clone)]
 enum Device {
     Message(),
     Memory(usize),
+    Display(usize),
+}
+
+#[pyclass]
+#[derive(Debug, Copy, Clone)]
+enum ImageFormat {
+    Rgba,
+    Png,
+}
+
+#[pyclass]
+#[derive(Debug, Copy, Clone)]
+enum CoercionMode {
+    Strict,
+    Lenient,
 }
 
 #[pyclass]
@@ -17,6 +41,8 @@ enum FinishReason {
     PcWrap,
     Halt,
     InsLimit,
+    TickLimit,
+    Breakpoint,
 }
 
 #[pyclass]
@@ -28,14 +54,109 @@ enum DeviceState {
     Memory {
         data: Vec<f64>,
     },
+    Display {
+        size: usize,
+        format: ImageFormat,
+        data: Vec<u8>,
+    },
+    Custom(),
+}
+
+#[pyclass]
+#[derive(Debug, Clone)]
+enum VariableValue {
+    Null(),
+    Num(f64),
+    Str(String),
+    Building(String),
+    Property(String),
+}
+
+/// Initial contents of a device, either the text of a message or the first memory cells.
+#[derive(Debug, Clone, FromPyObject)]
+enum DeviceContents {
+    Message(String),
+    Memory(Vec<f64>),
+}
+
+/// Initial value of a variable, `None` stands for null.
+#[derive(Debug, Clone, FromPyObject)]
+enum InitialValue {
+    Value(VariableValue),
+    Num(f64),
+    Str(String),
 }
 
 #[pyclass]
 #[derive(Debug, Clone)]
 pub enum ErrorPos {
-    Instruction(usize),
+    Instruction {
+        index: usize,
+        line: usize,
+        start_column: usize,
+        end_column: usize,
+    },
     None(),
     PcFetch(),
+    Parse {
+        line: usize,
+        column: usize,
+    },
+    Input {
+        line: usize,
+        column: usize,
+    },
+    Load(),
+}
+
+#[pyclass(get_all)]
+#[derive(Debug, Clone)]
+struct InstructionProfile {
+    index: usize,
+    line: usize,
+    opcode: &'static str,
+    executions: u64,
+    jumps_taken: Option<u64>,
+}
+
+#[pyclass]
+#[derive(Debug, Clone)]
+struct Profile {
+    #[pyo3(get)]
+    total: u64,
+    #[pyo3(get)]
+    instructions: Vec<InstructionProfile>,
+    /// Human-readable table of the executed instructions, most executed first.
+    #[pyo3(get)]
+    report: String,
+    profile: profile::Profile,
+}
+
+/// Line and branch coverage of one program, accumulated over runs.
+#[pyclass]
+#[derive(Debug, Default)]
+struct Coverage {
+    coverage: coverage::Coverage,
+}
+
+#[pymethods]
+impl Coverage {
+    #[new]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, profile: &Profile) {
+        self.coverage.add(&profile.profile);
+    }
+
+    pub fn to_lcov(&self, path: &str) -> String {
+        self.coverage.to_lcov(path)
+    }
+
+    pub fn to_cobertura(&self, path: &str) -> String {
+        self.coverage.to_cobertura(path)
+    }
 }
 
 #[pyclass]
@@ -45,13 +166,234 @@ enum ExecutionResult {
         finish_reason: FinishReason,
         devices: HashMap<String, DeviceState>,
         print_buffer: String,
+        ticks: u64,
+        seed: u64,
+        profile: Option<Profile>,
+        variables: Option<HashMap<String, VariableValue>>,
+    },
+    Failure {
+        pos: ErrorPos,
+        msg: String,
+        seed: Option<u64>,
+        profile: Option<Profile>,
+    },
+}
+
+#[pyclass]
+#[derive(Debug, Clone)]
+enum ProcessorResult {
+    Success {
+        finish_reason: FinishReason,
+        print_buffer: String,
     },
     Failure {
         pos: ErrorPos,
         msg: String,
+        seed: u64,
     },
 }
 
+#[pyclass(get_all)]
+#[derive(Debug, Clone)]
+struct WorldResult {
+    processors: Vec<ProcessorResult>,
+    devices: HashMap<String, DeviceState>,
+    ticks: u64,
+    seed: u64,
+}
+
+impl From<interface::VariableValue> for VariableValue {
+    fn from(value: interface::VariableValue) -> Self {
+        match value {
+            interface::VariableValue::Null => VariableValue::Null(),
+            interface::VariableValue::Num(num) => VariableValue::Num(num),
+            interface::VariableValue::Str(string) => VariableValue::Str(string),
+            interface::VariableValue::Building(name) => VariableValue::Building(name),
+            interface::VariableValue::Property(name) => VariableValue::Property(name),
+        }
+    }
+}
+
+impl From<Output> for ExecutionResult {
+    fn from(value: Output) -> Self {
+        match value {
+            Output::Success { finish_reason, devices, print_buffer, ticks, seed, profile, variables } =>
+                ExecutionResult::Success {
+                    finish_reason: finish_reason.into(),
+                    devices: devices.into_iter().map(|(k, v)| (k, v.into())).collect(),
+                    print_buffer,
+                    ticks,
+                    seed,
+                    profile: profile.map(Profile::from),
+                    variables: variables
+                        .map(|vars| vars.into_iter().map(|(k, v)| (k, v.into())).collect()),
+                },
+            Output::Failure { pos, msg, seed, profile } => ExecutionResult::Failure {
+                pos: pos.into(),
+                msg,
+                seed,
+                profile: profile.map(Profile::from),
+            },
+        }
+    }
+}
+
+impl From<VariableValue> for interface::VariableValue {
+    fn from(value: VariableValue) -> Self {
+        match value {
+            VariableValue::Null() => interface::VariableValue::Null,
+            VariableValue::Num(num) => interface::VariableValue::Num(num),
+            VariableValue::Str(string) => interface::VariableValue::Str(string),
+            VariableValue::Building(name) => interface::VariableValue::Building(name),
+            VariableValue::Property(name) => interface::VariableValue::Property(name),
+        }
+    }
+}
+
+impl InitialValue {
+    fn to_variable_value(value: Option<Self>) -> interface::VariableValue {
+        match value {
+            None => interface::VariableValue::Null,
+            Some(InitialValue::Value(value)) => value.into(),
+            Some(InitialValue::Num(num)) => interface::VariableValue::Num(num),
+            Some(InitialValue::Str(string)) => interface::VariableValue::Str(string),
+        }
+    }
+}
+
+impl From<DeviceContents> for interface::DeviceContents {
+    fn from(value: DeviceContents) -> Self {
+        match value {
+            DeviceContents::Message(text) => interface::DeviceContents::Message(text),
+            DeviceContents::Memory(data) => interface::DeviceContents::Memory(data),
+        }
+    }
+}
+
+impl From<profile::Profile> for Profile {
+    fn from(value: profile::Profile) -> Self {
+        Profile {
+            total: value.total,
+            report: value.report(),
+            instructions: value.instructions.iter()
+                .map(|p| InstructionProfile {
+                    index: p.index,
+                    line: p.line,
+                    opcode: p.opcode,
+                    executions: p.executions,
+                    jumps_taken: p.jumps_taken,
+                })
+                .collect(),
+            profile: value,
+        }
+    }
+}
+
+impl From<VmFinishReason> for FinishReason {
+    fn from(value: VmFinishReason) -> Self {
+        match value {
+            VmFinishReason::PcWrap => FinishReason::PcWrap,
+            VmFinishReason::Halt => FinishReason::Halt,
+            VmFinishReason::InsLimit => FinishReason::InsLimit,
+            VmFinishReason::TickLimit => FinishReason::TickLimit,
+            VmFinishReason::Breakpoint => FinishReason::Breakpoint,
+        }
+    }
+}
+
+impl From<ImageFormat> for interface::ImageFormat {
+    fn from(value: ImageFormat) -> Self {
+        match value {
+            ImageFormat::Rgba => interface::ImageFormat::Rgba,
+            ImageFormat::Png => interface::ImageFormat::Png,
+        }
+    }
+}
+
+impl From<interface::ImageFormat> for ImageFormat {
+    fn from(value: interface::ImageFormat) -> Self {
+        match value {
+            interface::ImageFormat::Rgba => ImageFormat::Rgba,
+            interface::ImageFormat::Png => ImageFormat::Png,
+        }
+    }
+}
+
+impl From<CoercionMode> for emulator::value::CoercionMode {
+    fn from(value: CoercionMode) -> Self {
+        match value {
+            CoercionMode::Strict => emulator::value::CoercionMode::Strict,
+            CoercionMode::Lenient => emulator::value::CoercionMode::Lenient,
+        }
+    }
+}
+
+impl From<Device> for interface::Device {
+    fn from(value: Device) -> Self {
+        match value {
+            Device::Message() => interface::Device::Message,
+            Device::Memory(capacity) => interface::Device::Memory(capacity),
+            Device::Display(size) => interface::Device::Display(size),
+        }
+    }
+}
+
+impl From<interface::DeviceState> for DeviceState {
+    fn from(value: interface::DeviceState) -> Self {
+        match value {
+            interface::DeviceState::Message(text) => DeviceState::Message { text },
+            interface::DeviceState::Memory(data) => DeviceState::Memory { data: data.to_vec() },
+            interface::DeviceState::Display { size, format, data } => DeviceState::Display {
+                size,
+                format: format.into(),
+                data,
+            },
+            interface::DeviceState::Custom => DeviceState::Custom(),
+        }
+    }
+}
+
+impl From<interface::ErrorPos> for ErrorPos {
+    fn from(value: interface::ErrorPos) -> Self {
+        match value {
+            interface::ErrorPos::Instruction { index, line, start_column, end_column } =>
+                ErrorPos::Instruction { index, line, start_column, end_column },
+            interface::ErrorPos::None => ErrorPos::None(),
+            interface::ErrorPos::PcFetch => ErrorPos::PcFetch(),
+            interface::ErrorPos::Parse { line, column } => ErrorPos::Parse { line, column },
+            interface::ErrorPos::Input { line, column } => ErrorPos::Input { line, column },
+            interface::ErrorPos::Load => ErrorPos::Load(),
+        }
+    }
+}
+
+impl From<interface::WorldOutput> for WorldResult {
+    fn from(value: interface::WorldOutput) -> Self {
+        WorldResult {
+            processors: value.processors.into_iter().map(ProcessorResult::from).collect(),
+            devices: value.devices.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            ticks: value.ticks,
+            seed: value.seed,
+        }
+    }
+}
+
+impl From<interface::ProcessorOutput> for ProcessorResult {
+    fn from(value: interface::ProcessorOutput) -> Self {
+        match value {
+            interface::ProcessorOutput::Success { finish_reason, print_buffer } => ProcessorResult::Success {
+                finish_reason: finish_reason.into(),
+                print_buffer,
+            },
+            interface::ProcessorOutput::Failure { pos, msg, seed } => ProcessorResult::Failure {
+                pos: pos.into(),
+                msg,
+                seed,
+            },
+        }
+    }
+}
+
 #[pyclass]
 #[derive(Debug, Clone)]
 struct Executor {
@@ -62,18 +404,49 @@ struct Executor {
     #[pyo3(set)]
     instruction_limit: Option<usize>,
     #[pyo3(set)]
+    tick_limit: Option<u64>,
+    #[pyo3(set)]
+    ipt: Option<usize>,
+    #[pyo3(set)]
     end_on_wrap: bool,
+    #[pyo3(set)]
+    image_format: ImageFormat,
+    #[pyo3(set)]
+    coercion: CoercionMode,
+    #[pyo3(set)]
+    seed: Option<u64>,
+    #[pyo3(set)]
+    trace_path: Option<String>,
+    #[pyo3(set)]
+    profile: bool,
+    #[pyo3(set)]
+    include_variables: bool,
+    /// Raise an `EmulatorError` subclass instead of returning `ExecutionResult.Failure`.
+    #[pyo3(set)]
+    raise_errors: bool,
+    variables: HashMap<String, interface::VariableValue>,
     devices: Vec<(String, interface::Device)>,
+    device_contents: HashMap<String, interface::DeviceContents>,
 }
 
 impl Executor {
-    fn get_options(&mut self) -> interface::Options {
+    fn get_options(&self) -> interface::Options {
         interface::Options {
-            code: std::mem::take(&mut self.code),
+            code: self.code.clone(),
             code_len_limit: self.code_len_limit,
             instruction_limit: self.instruction_limit,
+            tick_limit: self.tick_limit,
+            ipt: self.ipt,
             end_on_wrap: self.end_on_wrap,
-            devices: std::mem::take(&mut self.devices),
+            devices: self.devices.clone(),
+            image_format: Some(self.image_format.into()),
+            coercion: Some(self.coercion.into()),
+            seed: self.seed,
+            trace_path: self.trace_path.clone(),
+            profile: self.profile,
+            include_variables: self.include_variables,
+            variables: self.variables.clone(),
+            device_contents: self.device_contents.clone(),
         }
     }
 }
@@ -86,57 +459,498 @@ impl Executor {
             code,
             code_len_limit: None,
             instruction_limit: None,
+            tick_limit: None,
+            ipt: None,
             end_on_wrap: true,
+            image_format: ImageFormat::Rgba,
+            coercion: CoercionMode::Strict,
+            seed: None,
+            trace_path: None,
+            profile: false,
+            include_variables: false,
+            raise_errors: false,
+            variables: HashMap::new(),
             devices: vec![],
+            device_contents: HashMap::new(),
         }
     }
 
-    pub fn add_device(&mut self, name: String, device: Device) {
-        self.devices.push((name, match device {
-            Device::Message() => interface::Device::Message,
-            Device::Memory(capacity) => interface::Device::Memory(capacity),
-        }));
+    /// Adds a device, optionally with initial contents: a string for a message
+    /// or a list of numbers for the first cells of a memory.
+    /// Instead of a `Device`, any object with some of the methods `read(index)`, `write(index, value)`,
+    /// `sense(property)` and `print_flush(text)` can be linked as a custom building,
+    /// other objects raise `TypeError`.
+    #[pyo3(signature = (name, device, contents=None))]
+    pub fn add_device(&mut self, name: String, device: &Bound<PyAny>, contents: Option<DeviceContents>)
+        -> PyResult<()> {
+        let device = device_from_py(device)?;
+        if let Some(contents) = contents {
+            self.device_contents.insert(name.clone(), contents.into());
+        }
+        self.devices.push((name, device));
+        Ok(())
     }
 
-    pub fn execute(&mut self) -> ExecutionResult {
-        match interface::run_from_options(self.get_options()) {
-            Output::Success { finish_reason, devices, print_buffer } => ExecutionResult::Success {
-                finish_reason: match finish_reason {
-                    VmFinishReason::PcWrap => FinishReason::PcWrap,
-                    VmFinishReason::Halt => FinishReason::Halt,
-                    VmFinishReason::InsLimit => FinishReason::InsLimit,
-                },
-                devices: devices.into_iter().map(|(k, v)| (k, match v {
-                    interface::DeviceState::Message(text) => DeviceState::Message { text },
-                    interface::DeviceState::Memory(data) =>
-                        DeviceState::Memory { data: data.to_vec() },
-                })).collect(),
-                print_buffer,
-            },
-            Output::Failure { pos, msg } => ExecutionResult::Failure {
-                pos: match pos {
-                    interface::ErrorPos::Instruction(i) => ErrorPos::Instruction(i),
-                    interface::ErrorPos::None => ErrorPos::None(),
-                    interface::ErrorPos::PcFetch => ErrorPos::PcFetch(),
-                },
-                msg,
-            },
-        }
+    /// Sets the initial value of a variable used by the program, it is ignored if the program does not use it.
+    pub fn set_variable(&mut self, name: String, value: Option<InitialValue>) {
+        self.variables.insert(name, InitialValue::to_variable_value(value));
     }
 
-    pub fn execute_to_json(&mut self) -> String {
+    pub fn execute(&self) -> PyResult<ExecutionResult> {
+        let output = match interface::try_run_from_options(self.get_options()) {
+            Ok(success) => Output::from(success),
+            Err(failure) if self.raise_errors => return Err(errors::to_py_err(&failure.error)),
+            Err(failure) => Output::from(*failure),
+        };
+        Ok(output.into())
+    }
+
+    pub fn execute_to_json(&self) -> String {
         let result = interface::run_from_options(self.get_options());
         serde_json::to_string(&result).unwrap()
     }
 }
 
+#[pyclass]
+#[derive(Debug, Clone)]
+struct WorldExecutor {
+    #[pyo3(set)]
+    code_len_limit: Option<usize>,
+    #[pyo3(set)]
+    instruction_limit: Option<usize>,
+    #[pyo3(set)]
+    tick_limit: Option<u64>,
+    #[pyo3(set)]
+    end_on_wrap: bool,
+    #[pyo3(set)]
+    image_format: ImageFormat,
+    #[pyo3(set)]
+    coercion: CoercionMode,
+    #[pyo3(set)]
+    seed: Option<u64>,
+    /// Raise an `EmulatorError` subclass instead of returning `ProcessorResult.Failure`,
+    /// for the first processor that failed in the order they were added.
+    /// Its `processor` attribute is the index of the processor, `None` if the devices could not be set up.
+    #[pyo3(set)]
+    raise_errors: bool,
+    processors: Vec<interface::ProcessorOptions>,
+    devices: Vec<(String, interface::Device)>,
+    device_contents: HashMap<String, interface::DeviceContents>,
+}
+
+impl WorldExecutor {
+    fn get_options(&self) -> interface::WorldOptions {
+        interface::WorldOptions {
+            processors: self.processors.clone(),
+            code_len_limit: self.code_len_limit,
+            instruction_limit: self.instruction_limit,
+            tick_limit: self.tick_limit,
+            end_on_wrap: self.end_on_wrap,
+            devices: self.devices.clone(),
+            image_format: Some(self.image_format.into()),
+            coercion: Some(self.coercion.into()),
+            seed: self.seed,
+            device_contents: self.device_contents.clone(),
+        }
+    }
+}
+
+#[pymethods]
+impl WorldExecutor {
+    #[new]
+    pub fn new() -> Self {
+        WorldExecutor {
+            code_len_limit: None,
+            instruction_limit: None,
+            tick_limit: None,
+            end_on_wrap: true,
+            image_format: ImageFormat::Rgba,
+            coercion: CoercionMode::Strict,
+            seed: None,
+            raise_errors: false,
+            processors: vec![],
+            devices: vec![],
+            device_contents: HashMap::new(),
+        }
+    }
+
+    /// Adds a processor linked to the named devices, or to all devices if `links` is not given.
+    #[pyo3(signature = (code, ipt=None, links=None, variables=None))]
+    pub fn add_processor(&mut self, code: String, ipt: Option<usize>, links: Option<Vec<String>>,
+                         variables: Option<HashMap<String, Option<InitialValue>>>) {
+        self.processors.push(interface::ProcessorOptions {
+            code,
+            ipt,
+            links,
+            variables: variables.unwrap_or_default().into_iter()
+                .map(|(k, v)| (k, InitialValue::to_variable_value(v)))
+                .collect(),
+        });
+    }
+
+    /// Adds a device, optionally with initial contents like `Executor.add_device`.
+    #[pyo3(signature = (name, device, contents=None))]
+    pub fn add_device(&mut self, name: String, device: &Bound<PyAny>, contents: Option<DeviceContents>)
+        -> PyResult<()> {
+        let device = device_from_py(device)?;
+        if let Some(contents) = contents {
+            self.device_contents.insert(name.clone(), contents.into());
+        }
+        self.devices.push((name, device));
+        Ok(())
+    }
+
+    pub fn execute(&self) -> PyResult<WorldResult> {
+        if !self.raise_errors {
+            return Ok(interface::run_world_from_options(self.get_options()).into());
+        }
+        let run = interface::try_run_world_from_options(self.get_options())
+            .map_err(|failure| errors::with_run_index(errors::to_py_err(&failure.error), "processor", None))?;
+        if let Some((i, Err(failure))) = run.processors.iter().enumerate().find(|(_, output)| output.is_err()) {
+            return Err(errors::with_run_index(errors::to_py_err(&failure.error), "processor", Some(i)));
+        }
+        Ok(interface::WorldOutput::from(run).into())
+    }
+
+    pub fn execute_to_json(&self) -> String {
+        let result = interface::run_world_from_options(self.get_options());
+        serde_json::to_string(&result).unwrap()
+    }
+}
+
+/// A building implemented by a Python object with any of the methods `read(index)`,
+/// `write(index, value)`, `sense(property)` and `print_flush(text)`.
+#[derive(Debug)]
+struct PyBuilding {
+    name: String,
+    object: Py<PyAny>,
+}
+
+impl PyBuilding {
+    const METHODS: [&'static str; 4] = ["read", "write", "sense", "print_flush"];
+
+    /// Calls a method of the object and converts its result, returns `None` if it does not have one.
+    fn call<T>(&self, method: &str, args: impl FnOnce(Python) -> PyResult<Vec<PyObject>>,
+               convert: impl FnOnce(&Bound<PyAny>) -> VmResult<T>) -> Option<VmResult<T>> {
+        Python::with_gil(|py| {
+            let object = self.object.bind(py);
+            let external = |err: PyErr| VmError::External(self.name.clone(), err.to_string());
+            match object.hasattr(method) {
+                Ok(true) => {},
+                Ok(false) => return None,
+                Err(err) => return Some(Err(external(err))),
+            }
+            let result = args(py)
+                .and_then(|args| object.call_method1(method, PyTuple::new(py, args)?))
+                .map_err(external)
+                .and_then(|result| convert(&result));
+            Some(result)
+        })
+    }
+
+    /// Converts the result of a method that returns a value.
+    fn returned_value(&self, method: &str, result: &Bound<PyAny>) -> VmResult<Value> {
+        py_to_value(result).ok_or_else(|| VmError::External(
+            self.name.clone(), format!("{}() returned an unsupported value", method)))
+    }
+}
+
+fn value_to_py(py: Python, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Num(num) => num.into_pyobject(py)?.into_any().unbind(),
+        Value::Str(string) => string.to_string().into_pyobject(py)?.into_any().unbind(),
+        value => Py::new(py, VariableValue::from(interface::VariableValue::from(value)))?.into_any(),
+    })
+}
+
+/// Converts `None`, numbers and strings.
+fn py_to_value(object: &Bound<PyAny>) -> Option<Value> {
+    if object.is_none() {
+        Some(Value::Null)
+    } else if let Ok(num) = object.extract::<f64>() {
+        Some(Value::Num(num))
+    } else if let Ok(string) = object.extract::<String>() {
+        Some(Value::Str(Arc::new(LazyUtf16String::new(Arc::new(string)))))
+    } else {
+        None
+    }
+}
+
+impl Building for PyBuilding {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn print_flush(&self, string: String) -> VmResult<()> {
+        self.call("print_flush", |py| Ok(vec![string.into_pyobject(py)?.into_any().unbind()]), |_| Ok(()))
+            .unwrap_or_else(|| Err(VmError::InvalidBuildingType("print flush into", self.name.clone())))
+    }
+
+    fn read(&self, index: Value) -> VmResult<Value> {
+        self.call("read", |py| Ok(vec![value_to_py(py, &index)?]),
+                  |result| self.returned_value("read", result))
+            .unwrap_or_else(|| Err(VmError::InvalidBuildingType("read from", self.name.clone())))
+    }
+
+    fn write(&self, index: Value, value: Value) -> VmResult<()> {
+        self.call("write", |py| Ok(vec![value_to_py(py, &index)?, value_to_py(py, &value)?]), |_| Ok(()))
+            .unwrap_or_else(|| Err(VmError::InvalidBuildingType("write into", self.name.clone())))
+    }
+
+    fn sense(&self, property: Property) -> VmResult<Value> {
+        self.call("sense", |py| Ok(vec![property.name().into_pyobject(py)?.into_any().unbind()]),
+                  |result| self.returned_value("sense", result))
+            .unwrap_or_else(|| Err(VmError::InvalidBuildingType("sense from", self.name.clone())))
+    }
+}
+
+#[derive(Debug)]
+struct PyBuildingFactory(Py<PyAny>);
+
+impl interface::BuildingFactory for PyBuildingFactory {
+    fn build(&self, name: String) -> Arc<dyn Building> {
+        Python::with_gil(|py| Arc::new(PyBuilding {
+            name,
+            object: self.0.clone_ref(py),
+        }))
+    }
+}
+
+/// Accepts a `Device` or any object implementing some of the methods of `PyBuilding`.
+fn device_from_py(device: &Bound<PyAny>) -> PyResult<interface::Device> {
+    if let Ok(device) = device.extract::<Device>() {
+        return Ok(device.into());
+    }
+    for method in PyBuilding::METHODS {
+        if device.hasattr(method)? {
+            return Ok(interface::Device::Custom(Arc::new(PyBuildingFactory(device.clone().unbind()))));
+        }
+    }
+    Err(PyTypeError::new_err(format!(
+        "Expected a Device or an object with any of the methods {}, got {}",
+        PyBuilding::METHODS.join(", "), device.get_type().name()?)))
+}
+
+type LinkedDevices = HashMap<String, (Arc<dyn Building>, interface::DeviceStateGetter)>;
+
+/// Everything needed to load a `Vm` again on reset.
+/// Custom buildings are not rebuilt, the same Python object backs every load.
+struct VmConfig {
+    code: String,
+    code_len_limit: usize,
+    ipt: usize,
+    coercion: CoercionMode,
+    seed: u64,
+    image_format: ImageFormat,
+    devices: Vec<(String, interface::Device)>,
+}
+
+impl VmConfig {
+    fn load(&self) -> PyResult<(VM, LinkedDevices)> {
+        let mut links = vec![];
+        let mut devices = HashMap::new();
+        for (name, device) in &self.devices {
+            let (building, getter) = device.clone()
+                .construct(name.clone(), self.image_format.into(), None)
+                .map_err(py_err)?;
+            links.push(building.clone());
+            devices.insert(name.clone(), (building, getter));
+        }
+        let mut vm = VM::new(&self.code, self.code_len_limit, links).map_err(py_err)?;
+        vm.set_coercion_mode(self.coercion.into());
+        vm.set_ipt(self.ipt);
+        vm.set_seed(self.seed);
+        Ok((vm, devices))
+    }
+}
+
+/// State of a `Vm`, replaced as a whole on reset.
+struct VmState {
+    vm: VM,
+    devices: LinkedDevices,
+}
+
+impl VmState {
+    fn device(&self, name: &str) -> PyResult<&(Arc<dyn Building>, interface::DeviceStateGetter)> {
+        self.devices.get(name).ok_or_else(|| py_err(VmError::DeviceNotFound(name.to_string())))
+    }
+}
+
+/// A processor that keeps its state between calls, for driving programs interactively.
+/// It can be used from any thread, but only by one at a time.
+#[pyclass]
+struct Vm {
+    config: VmConfig,
+    state: Mutex<VmState>,
+}
+
+fn py_err(err: VmError) -> PyErr {
+    errors::to_py_err(&err.to_pos())
+}
+
+impl Vm {
+    /// Fails instead of blocking if another thread is using the processor,
+    /// which could be waiting for the GIL held by the caller.
+    fn state(&self) -> PyResult<MutexGuard<'_, VmState>> {
+        match self.state.try_lock() {
+            Ok(state) => Ok(state),
+            Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+            Err(TryLockError::WouldBlock) =>
+                Err(PyRuntimeError::new_err("Vm is being used by another thread")),
+        }
+    }
+}
+
+#[pymethods]
+impl Vm {
+    /// Loads the program, linked to new devices given as `(name, device)` pairs.
+    /// Devices can be custom buildings like in `Executor.add_device`, which keep their state across `reset`.
+    #[new]
+    #[pyo3(signature = (code, devices=None, code_len_limit=None, ipt=None, coercion=CoercionMode::Strict,
+                        seed=None, image_format=ImageFormat::Rgba))]
+    pub fn new(code: String, devices: Option<Vec<(String, Bound<PyAny>)>>, code_len_limit: Option<usize>,
+               ipt: Option<usize>, coercion: CoercionMode, seed: Option<u64>,
+               image_format: ImageFormat) -> PyResult<Self> {
+        let config = VmConfig {
+            code,
+            code_len_limit: code_len_limit.unwrap_or(VM::DEFAULT_CODE_LEN_LIMIT),
+            ipt: ipt.unwrap_or(VM::DEFAULT_IPT),
+            coercion,
+            seed: seed.unwrap_or_else(Rng::random_seed),
+            image_format,
+            devices: devices.unwrap_or_default().into_iter()
+                .map(|(name, device)| Ok((name, device_from_py(&device)?)))
+                .collect::<PyResult<_>>()?,
+        };
+        let (vm, devices) = config.load()?;
+        Ok(Vm { config, state: Mutex::new(VmState { vm, devices }) })
+    }
+
+    /// Restores the state right after loading: variables, devices, clock and random numbers.
+    /// Custom buildings are linked again as the same Python objects, so they keep their state.
+    pub fn reset(&self) -> PyResult<()> {
+        let (vm, devices) = self.config.load()?;
+        *self.state()? = VmState { vm, devices };
+        Ok(())
+    }
+
+    #[getter]
+    pub fn seed(&self) -> u64 {
+        self.config.seed
+    }
+
+    /// Executes up to `n` instructions, returning the reason if the program finished.
+    #[pyo3(signature = (n=1, end_on_wrap=false))]
+    pub fn step(&self, n: usize, end_on_wrap: bool) -> PyResult<Option<FinishReason>> {
+        let state = self.state()?;
+        for _ in 0..n {
+            if let Some(reason) = state.vm.step(None, end_on_wrap).map_err(|err| errors::to_py_err(&err))? {
+                return Ok(Some(reason.into()));
+            }
+        }
+        Ok(None)
+    }
+
+    #[pyo3(signature = (limit=None, tick_limit=None, end_on_wrap=false))]
+    pub fn run(&self, limit: Option<usize>, tick_limit: Option<u64>, end_on_wrap: bool) -> PyResult<FinishReason> {
+        self.state()?.vm.run(limit, tick_limit, end_on_wrap)
+            .map(FinishReason::from)
+            .map_err(|err| errors::to_py_err(&err))
+    }
+
+    pub fn get_var(&self, name: &str) -> PyResult<VariableValue> {
+        let value = self.state()?.vm.get_val(name).map_err(py_err)?;
+        Ok(interface::VariableValue::from(&value).into())
+    }
+
+    pub fn set_var(&self, name: &str, value: Option<InitialValue>) -> PyResult<()> {
+        let state = self.state()?;
+        let value = InitialValue::to_variable_value(value).to_value(&state.vm).map_err(py_err)?;
+        state.vm.set_var(name, value).map_err(py_err)
+    }
+
+    pub fn read_device(&self, name: &str) -> PyResult<DeviceState> {
+        let state = self.state()?;
+        let (_, getter) = state.device(name)?;
+        Ok(getter().into())
+    }
+
+    /// Replaces the text of a message or the first cells of a memory.
+    pub fn write_device(&self, name: &str, contents: DeviceContents) -> PyResult<()> {
+        let state = self.state()?;
+        let (building, _) = state.device(name)?;
+        match contents {
+            DeviceContents::Message(text) => building.print_flush(text),
+            DeviceContents::Memory(data) => data.into_iter().enumerate()
+                .try_for_each(|(i, value)| building.write(Value::Num(i as f64), Value::Num(value))),
+        }.map_err(py_err)
+    }
+
+    /// Returns and clears the text printed since the last flush.
+    pub fn take_print_buffer(&self) -> PyResult<String> {
+        Ok(self.state()?.vm.print_buffer().take())
+    }
+
+    #[getter]
+    pub fn ticks(&self) -> PyResult<u64> {
+        Ok(self.state()?.vm.ticks())
+    }
+
+    /// Index of the next instruction.
+    #[getter]
+    pub fn pc(&self) -> PyResult<Option<usize>> {
+        Ok(self.state()?.vm.pc())
+    }
+}
+
+/// Runs the configured executors on `threads` threads, by default one per CPU, without holding the GIL.
+/// Results are returned in the order of `configs`. With `raise_errors`, the error of the first config
+/// that failed is raised once all have run, with its position in the `config` attribute.
+/// The `raise_errors` of the executors themselves is ignored.
+/// Executors cannot share a `trace_path`, which would interleave their traces.
+/// Custom buildings take the GIL whenever they are used.
+#[pyfunction]
+#[pyo3(signature = (configs, threads=None, raise_errors=false))]
+fn run_batch(py: Python, configs: Vec<PyRef<Executor>>, threads: Option<usize>, raise_errors: bool)
+    -> PyResult<Vec<ExecutionResult>> {
+    let mut trace_paths = HashSet::new();
+    let mut paths = configs.iter().filter_map(|config| config.trace_path.as_ref());
+    if let Some(path) = paths.find(|path| !trace_paths.insert(*path)) {
+        return Err(PyValueError::new_err(format!("trace_path '{}' is used by several executors", path)));
+    }
+    let jobs = configs.iter().map(|config| config.get_options()).collect::<Vec<_>>();
+    let threads = threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let outputs = py.allow_threads(move || interface::run_batch(jobs, threads));
+    if raise_errors {
+        if let Some((i, Err(failure))) = outputs.iter().enumerate().find(|(_, output)| output.is_err()) {
+            return Err(errors::with_run_index(errors::to_py_err(&failure.error), "config", Some(i)));
+        }
+    }
+    Ok(outputs.into_iter()
+        .map(|output| output.map_or_else(|failure| Output::from(*failure), Output::from).into())
+        .collect())
+}
+
 #[pymodule]
 fn mlog_emulator(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Device>()?;
+    m.add_class::<ImageFormat>()?;
+    m.add_class::<CoercionMode>()?;
     m.add_class::<Executor>()?;
     m.add_class::<FinishReason>()?;
     m.add_class::<DeviceState>()?;
+    m.add_class::<VariableValue>()?;
     m.add_class::<ErrorPos>()?;
+    m.add_class::<InstructionProfile>()?;
+    m.add_class::<Profile>()?;
+    m.add_class::<Coverage>()?;
     m.add_class::<ExecutionResult>()?;
+    m.add_class::<WorldExecutor>()?;
+    m.add_class::<ProcessorResult>()?;
+    m.add_class::<WorldResult>()?;
+    m.add_class::<Vm>()?;
+    m.add_function(wrap_pyfunction!(run_batch, m)?)?;
+    errors::register(m)?;
     Ok(())
 }
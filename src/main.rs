@@ -1,6 +1,11 @@
 use std::io::{stdin, stdout};
+use std::process::ExitCode;
 use emulator::interface::run_from_json;
 
-fn main() {
-    run_from_json(stdin(), stdout());
+fn main() -> ExitCode {
+    if run_from_json(stdin(), stdout()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
 }